@@ -0,0 +1,637 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::node::NodeStopped;
+
+/// Blobs are moved around in pieces this big.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// How many chunk requests a download keeps in flight at once.
+const WINDOW: usize = 8;
+/// Nobody gets to make us track more chunks than this for one blob.
+const MAX_CHUNKS: u64 = 64 * 1024;
+/// How many blobs we remember neighbors having. Past this, blobs we aren't
+/// fetching make room for newly announced ones.
+const MAX_KNOWN_BLOBS: usize = 4096;
+/// How long what a timed out fetch got is kept, for fetching it again to
+/// pick up from.
+const KEEP_PARTIAL: Duration = Duration::from_secs(300);
+
+/// A sha256 hash. Chunks are named by the hash of their contents, blobs by
+/// the hash of their manifest.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobId(pub [u8; 32]);
+
+impl BlobId {
+    pub fn hash(bytes: &[u8]) -> Self {
+        let mut id = [0; 32];
+        id.copy_from_slice(&Sha256::digest(bytes));
+        BlobId(id)
+    }
+}
+
+impl fmt::Debug for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0[..6] {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, "..")
+    }
+}
+
+/// What a blob is made of. Fetched first, then used to check every chunk as
+/// it comes in.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Manifest {
+    pub len: u64,
+    pub chunks: Vec<BlobId>,
+}
+
+impl Manifest {
+    pub fn id(&self) -> BlobId {
+        BlobId::hash(&bincode::serialize(self).expect("manifests always serialize"))
+    }
+
+    fn chunk_len(&self, index: usize) -> usize {
+        let start = index * CHUNK_SIZE;
+        (self.len as usize - start).min(CHUNK_SIZE)
+    }
+
+    /// Whether the chunk list fits the length, so we don't trust a manifest
+    /// that would have us index out of bounds (or allocate forever).
+    fn is_sane(&self) -> bool {
+        let chunks = self.len.div_ceil(CHUNK_SIZE as u64);
+        chunks <= MAX_CHUNKS && chunks == self.chunks.len() as u64
+    }
+}
+
+/// Blob transfer messages, exchanged between neighbors.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum BlobMsg {
+    /// "I have these, ask me for them".
+    Have(Vec<BlobId>),
+    GetManifest(BlobId),
+    Manifest(Manifest),
+    GetChunk {
+        blob: BlobId,
+        index: u32,
+    },
+    Chunk {
+        blob: BlobId,
+        index: u32,
+        data: Vec<u8>,
+    },
+    /// Answer to either get if we don't have the blob after all.
+    NotFound(BlobId),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlobError {
+    /// Didn't get the whole blob in time. What did arrive is kept, so
+    /// fetching it again picks up where this left off.
+    Timeout,
+    /// Our own node shut down before the fetch finished.
+    NodeStopped(NodeStopped),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Timeout => write!(f, "blob fetch timed out"),
+            BlobError::NodeStopped(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+impl From<NodeStopped> for BlobError {
+    fn from(e: NodeStopped) -> Self {
+        BlobError::NodeStopped(e)
+    }
+}
+
+struct Blob {
+    manifest: Manifest,
+    data: Vec<u8>,
+}
+
+type Waiter = (Instant, oneshot::Sender<Result<Vec<u8>, BlobError>>);
+
+/// A blob we're putting together.
+#[derive(Default)]
+struct Download {
+    manifest: Option<Manifest>,
+    chunks: Vec<Option<Vec<u8>>>,
+    /// Outstanding requests: chunk index (None for the manifest), who we
+    /// asked and when.
+    in_flight: HashMap<Option<u32>, (SocketAddr, Instant)>,
+    waiters: Vec<Waiter>,
+    /// When someone last asked for the blob.
+    wanted: Option<Instant>,
+}
+
+/// Blob messages for the node to put on the link to each neighbor.
+pub type Outbox = Vec<(SocketAddr, BlobMsg)>;
+
+/// The blobs we hold, which neighbors have announced which blobs, and the
+/// fetches still under way. Chunks are only asked of direct neighbors, so
+/// everything it wants sent is for one of the links it was told about.
+pub struct BlobStore {
+    blobs: HashMap<BlobId, Blob>,
+    neighbors: HashSet<SocketAddr>,
+    holders: HashMap<BlobId, HashSet<SocketAddr>>,
+    downloads: HashMap<BlobId, Download>,
+    /// Requests unanswered for this long are sent again, to someone else if
+    /// possible.
+    request_timeout: Duration,
+    keep_partial: Duration,
+}
+
+impl BlobStore {
+    pub fn new(request_timeout: Duration) -> Self {
+        Self {
+            blobs: Default::default(),
+            neighbors: Default::default(),
+            holders: Default::default(),
+            downloads: Default::default(),
+            request_timeout,
+            keep_partial: KEEP_PARTIAL,
+        }
+    }
+
+    /// Store `data` and tell the neighbors about it.
+    pub fn put(&mut self, data: Vec<u8>) -> (BlobId, Outbox) {
+        let manifest = Manifest {
+            len: data.len() as u64,
+            chunks: data.chunks(CHUNK_SIZE).map(BlobId::hash).collect(),
+        };
+        let id = manifest.id();
+        self.blobs.insert(id, Blob { manifest, data });
+        (id, self.announce(id))
+    }
+
+    fn announce(&self, id: BlobId) -> Outbox {
+        self.neighbors
+            .iter()
+            .map(|n| (*n, BlobMsg::Have(vec![id])))
+            .collect()
+    }
+
+    /// A new neighbor, let it know what we have.
+    pub fn add_peer(&mut self, peer: SocketAddr) -> Outbox {
+        self.neighbors.insert(peer);
+        if self.blobs.is_empty() {
+            return vec![];
+        }
+        vec![(peer, BlobMsg::Have(self.blobs.keys().copied().collect()))]
+    }
+
+    /// A neighbor is gone. Whatever we'd asked it for gets asked of someone
+    /// else on the next `poll`.
+    pub fn link_down(&mut self, peer: &SocketAddr) {
+        self.neighbors.remove(peer);
+        for holders in self.holders.values_mut() {
+            holders.remove(peer);
+        }
+        self.holders.retain(|_, holders| !holders.is_empty());
+        for download in self.downloads.values_mut() {
+            download.in_flight.retain(|_, (to, _)| to != peer);
+        }
+    }
+
+    /// Get blob `id` from whichever neighbors have it, replying on `reply`
+    /// once it's all here and checked, or when `deadline` passes.
+    pub fn fetch(
+        &mut self,
+        id: BlobId,
+        deadline: Instant,
+        reply: oneshot::Sender<Result<Vec<u8>, BlobError>>,
+    ) -> Outbox {
+        if let Some(blob) = self.blobs.get(&id) {
+            let _ = reply.send(Ok(blob.data.clone()));
+            return vec![];
+        }
+        let download = self.downloads.entry(id).or_default();
+        download.waiters.push((deadline, reply));
+        download.wanted = Some(Instant::now());
+        self.step(id)
+    }
+
+    /// Handle a message from neighbor `from`.
+    pub fn handle(&mut self, from: SocketAddr, msg: BlobMsg) -> Outbox {
+        match msg {
+            BlobMsg::Have(ids) => {
+                let mut out = Vec::new();
+                for id in ids {
+                    self.holder(id, from);
+                    out.extend(self.step(id));
+                }
+                out
+            }
+            BlobMsg::GetManifest(id) => match self.blobs.get(&id) {
+                Some(blob) => vec![(from, BlobMsg::Manifest(blob.manifest.clone()))],
+                None => vec![(from, BlobMsg::NotFound(id))],
+            },
+            BlobMsg::GetChunk { blob: id, index } => {
+                let chunk = self.blobs.get(&id).and_then(|blob| {
+                    let start = index as usize * CHUNK_SIZE;
+                    (start < blob.data.len()).then(|| {
+                        let end = (start + CHUNK_SIZE).min(blob.data.len());
+                        blob.data[start..end].to_vec()
+                    })
+                });
+                match chunk {
+                    Some(data) => vec![(
+                        from,
+                        BlobMsg::Chunk {
+                            blob: id,
+                            index,
+                            data,
+                        },
+                    )],
+                    None => vec![(from, BlobMsg::NotFound(id))],
+                }
+            }
+            BlobMsg::Manifest(manifest) => {
+                let id = manifest.id();
+                let download = match self.downloads.get_mut(&id) {
+                    Some(d) if d.manifest.is_none() => d,
+                    _ => return vec![],
+                };
+                download.in_flight.remove(&None);
+                if !manifest.is_sane() {
+                    // it hashes right but makes no sense, don't ask them again
+                    if let Some(holders) = self.holders.get_mut(&id) {
+                        holders.remove(&from);
+                    }
+                    return self.step(id);
+                }
+                download.chunks = vec![None; manifest.chunks.len()];
+                download.manifest = Some(manifest);
+                self.step(id)
+            }
+            BlobMsg::Chunk {
+                blob: id,
+                index,
+                data,
+            } => {
+                let download = match self.downloads.get_mut(&id) {
+                    Some(d) => d,
+                    None => return vec![],
+                };
+                let manifest = match &download.manifest {
+                    Some(m) => m,
+                    None => return vec![],
+                };
+                let i = index as usize;
+                if i >= manifest.chunks.len() {
+                    return vec![];
+                }
+                download.in_flight.remove(&Some(index));
+                // a bad chunk just gets asked for again
+                if data.len() == manifest.chunk_len(i) && BlobId::hash(&data) == manifest.chunks[i]
+                {
+                    download.chunks[i] = Some(data);
+                }
+                if download.chunks.iter().all(|c| c.is_some()) {
+                    return self.finish(id);
+                }
+                self.step(id)
+            }
+            BlobMsg::NotFound(id) => {
+                if let Some(holders) = self.holders.get_mut(&id) {
+                    holders.remove(&from);
+                    if holders.is_empty() {
+                        self.holders.remove(&id);
+                    }
+                }
+                if let Some(download) = self.downloads.get_mut(&id) {
+                    download.in_flight.retain(|_, (to, _)| *to != from);
+                }
+                self.step(id)
+            }
+        }
+    }
+
+    /// `peer` has blob `id`.
+    fn holder(&mut self, id: BlobId, peer: SocketAddr) {
+        if self.holders.len() >= MAX_KNOWN_BLOBS && !self.holders.contains_key(&id) {
+            let downloads = &self.downloads;
+            let idle = self
+                .holders
+                .keys()
+                .find(|k| !downloads.contains_key(k))
+                .copied();
+            match idle {
+                Some(idle) => {
+                    self.holders.remove(&idle);
+                }
+                None => return,
+            }
+        }
+        self.holders.entry(id).or_default().insert(peer);
+    }
+
+    /// Send out requests for whatever a download still needs, spread over
+    /// the neighbors that have the blob. Downloads nobody is waiting on any
+    /// more sit still until they're fetched again.
+    fn step(&mut self, id: BlobId) -> Outbox {
+        let download = match self.downloads.get_mut(&id) {
+            Some(d) if !d.waiters.is_empty() => d,
+            _ => return vec![],
+        };
+        let holders: Vec<_> = match self.holders.get(&id) {
+            Some(h) if !h.is_empty() => h.iter().copied().collect(),
+            _ => return vec![],
+        };
+        let mut load: HashMap<SocketAddr, usize> = holders.iter().map(|h| (*h, 0)).collect();
+        for (to, _) in download.in_flight.values() {
+            if let Some(n) = load.get_mut(to) {
+                *n += 1;
+            }
+        }
+        let mut least_busy = || {
+            let (peer, n) = load.iter_mut().min_by_key(|(_, n)| **n)?;
+            *n += 1;
+            Some(*peer)
+        };
+
+        let mut out = Vec::new();
+        match &download.manifest {
+            None => {
+                if let Entry::Vacant(slot) = download.in_flight.entry(None) {
+                    if let Some(peer) = least_busy() {
+                        slot.insert((peer, Instant::now()));
+                        out.push((peer, BlobMsg::GetManifest(id)));
+                    }
+                }
+            }
+            Some(_) => {
+                let wanted: Vec<_> = download
+                    .chunks
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, c)| {
+                        c.is_none() && !download.in_flight.contains_key(&Some(*i as u32))
+                    })
+                    .map(|(i, _)| i as u32)
+                    .take(WINDOW.saturating_sub(download.in_flight.len()))
+                    .collect();
+                for index in wanted {
+                    if let Some(peer) = least_busy() {
+                        download
+                            .in_flight
+                            .insert(Some(index), (peer, Instant::now()));
+                        out.push((peer, BlobMsg::GetChunk { blob: id, index }));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Every chunk is in: put the blob together, hand it to whoever's waiting
+    /// and let the neighbors know we have it now.
+    fn finish(&mut self, id: BlobId) -> Outbox {
+        let download = self.downloads.remove(&id).expect("finishing a download");
+        let manifest = download.manifest.expect("chunks come after the manifest");
+        let data: Vec<u8> = download.chunks.into_iter().flatten().flatten().collect();
+        for (_, reply) in download.waiters {
+            let _ = reply.send(Ok(data.clone()));
+        }
+        self.blobs.insert(id, Blob { manifest, data });
+        self.announce(id)
+    }
+
+    /// Time out fetches past their deadline and requests that went
+    /// unanswered, and send whatever requests that frees up. Downloads nobody
+    /// has asked for in `KEEP_PARTIAL` are thrown away.
+    pub fn poll(&mut self) -> Outbox {
+        let now = Instant::now();
+        let timeout = self.request_timeout;
+        let ids: Vec<_> = self.downloads.keys().copied().collect();
+        let mut out = Vec::new();
+        for id in ids {
+            let download = self.downloads.get_mut(&id).expect("just listed");
+            let (expired, waiting): (Vec<_>, Vec<_>) = download
+                .waiters
+                .drain(..)
+                .partition(|(deadline, _)| *deadline <= now);
+            download.waiters = waiting;
+            for (_, reply) in expired {
+                let _ = reply.send(Err(BlobError::Timeout));
+            }
+            download
+                .in_flight
+                .retain(|_, (_, sent)| sent.elapsed() < timeout);
+            out.extend(self.step(id));
+        }
+        let keep = self.keep_partial;
+        self.downloads
+            .retain(|_, d| !d.waiters.is_empty() || d.wanted.is_some_and(|at| at.elapsed() < keep));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::testing::addr;
+
+    /// Stores on ports `1..=n`, all neighbors of each other.
+    fn stores(n: u16) -> HashMap<SocketAddr, BlobStore> {
+        let mut stores: HashMap<_, _> = (1..=n)
+            .map(|p| (addr(p), BlobStore::new(Duration::from_secs(60))))
+            .collect();
+        for a in 1..=n {
+            for b in 1..=n {
+                if a != b {
+                    stores.get_mut(&addr(a)).unwrap().add_peer(addr(b));
+                }
+            }
+        }
+        stores
+    }
+
+    /// Deliver what `from` wants sent, and everything that sets off, until
+    /// it's all quiet. Returns who got how many messages.
+    fn pump(
+        stores: &mut HashMap<SocketAddr, BlobStore>,
+        from: SocketAddr,
+        out: Outbox,
+    ) -> HashMap<SocketAddr, usize> {
+        let mut received = HashMap::new();
+        let mut queue: VecDeque<_> = out.into_iter().map(|(to, msg)| (from, to, msg)).collect();
+        while let Some((from, to, msg)) = queue.pop_front() {
+            *received.entry(to).or_default() += 1;
+            if let Some(store) = stores.get_mut(&to) {
+                let out = store.handle(from, msg);
+                queue.extend(out.into_iter().map(|(next, msg)| (to, next, msg)));
+            }
+        }
+        received
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    type Reply = oneshot::Receiver<Result<Vec<u8>, BlobError>>;
+
+    fn fetch(store: &mut BlobStore, id: BlobId, timeout: Duration) -> (Outbox, Reply) {
+        let (tx, rx) = oneshot::channel();
+        (store.fetch(id, Instant::now() + timeout, tx), rx)
+    }
+
+    #[test]
+    fn blobs_are_chunked_and_put_back_together() {
+        let mut stores = stores(2);
+        let blob = data(2 * CHUNK_SIZE + 100);
+        let (id, out) = stores.get_mut(&addr(1)).unwrap().put(blob.clone());
+        assert_eq!(out, vec![(addr(2), BlobMsg::Have(vec![id]))]);
+        let manifest = stores[&addr(1)].blobs[&id].manifest.clone();
+        assert_eq!(manifest.chunks.len(), 3);
+        assert_eq!(manifest.chunk_len(2), 100);
+        pump(&mut stores, addr(1), out);
+
+        let (out, mut rx) = fetch(
+            stores.get_mut(&addr(2)).unwrap(),
+            id,
+            Duration::from_secs(60),
+        );
+        pump(&mut stores, addr(2), out);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), blob);
+        assert_eq!(stores[&addr(2)].blobs[&id].data, blob);
+    }
+
+    #[test]
+    fn chunks_come_from_every_holder() {
+        let mut stores = stores(3);
+        let blob = data(4 * CHUNK_SIZE);
+        for holder in [1, 2] {
+            let (id, out) = stores.get_mut(&addr(holder)).unwrap().put(blob.clone());
+            pump(&mut stores, addr(holder), out);
+            assert_eq!(stores[&addr(3)].holders[&id].len(), holder as usize);
+        }
+        let id = stores[&addr(1)].blobs.keys().copied().next().unwrap();
+        let (out, mut rx) = fetch(
+            stores.get_mut(&addr(3)).unwrap(),
+            id,
+            Duration::from_secs(60),
+        );
+        let asked = pump(&mut stores, addr(3), out);
+        assert!(asked[&addr(1)] >= 2 && asked[&addr(2)] >= 2, "{:?}", asked);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), blob);
+    }
+
+    #[test]
+    fn holders_that_lost_the_blob_are_skipped() {
+        let mut stores = stores(3);
+        let blob = data(CHUNK_SIZE + 1);
+        let (id, out) = stores.get_mut(&addr(1)).unwrap().put(blob.clone());
+        pump(&mut stores, addr(1), out);
+        // 2 claims to have it but doesn't
+        stores
+            .get_mut(&addr(3))
+            .unwrap()
+            .handle(addr(2), BlobMsg::Have(vec![id]));
+        let (out, mut rx) = fetch(
+            stores.get_mut(&addr(3)).unwrap(),
+            id,
+            Duration::from_secs(60),
+        );
+        pump(&mut stores, addr(3), out);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), blob);
+        assert!(!stores[&addr(3)].holders[&id].contains(&addr(2)));
+    }
+
+    #[test]
+    fn fetching_a_blob_nobody_has_times_out() {
+        let mut stores = stores(2);
+        let id = BlobId::hash(b"nowhere");
+        let (out, mut rx) = fetch(stores.get_mut(&addr(1)).unwrap(), id, Duration::ZERO);
+        assert!(out.is_empty());
+        assert!(rx.try_recv().is_err());
+        stores.get_mut(&addr(1)).unwrap().poll();
+        assert_eq!(rx.try_recv().unwrap().unwrap_err(), BlobError::Timeout);
+    }
+
+    #[test]
+    fn bad_chunks_are_asked_for_again() {
+        let mut stores = stores(2);
+        let blob = data(100);
+        let (id, out) = stores.get_mut(&addr(1)).unwrap().put(blob.clone());
+        pump(&mut stores, addr(1), out);
+        let two = stores.get_mut(&addr(2)).unwrap();
+        let (out, mut rx) = fetch(two, id, Duration::from_secs(60));
+        assert_eq!(out, vec![(addr(1), BlobMsg::GetManifest(id))]);
+        let manifest = stores[&addr(1)].blobs[&id].manifest.clone();
+        let two = stores.get_mut(&addr(2)).unwrap();
+        let out = two.handle(addr(1), BlobMsg::Manifest(manifest));
+        assert_eq!(
+            out,
+            vec![(addr(1), BlobMsg::GetChunk { blob: id, index: 0 })]
+        );
+        let forged = BlobMsg::Chunk {
+            blob: id,
+            index: 0,
+            data: vec![0; 100],
+        };
+        let out = two.handle(addr(1), forged);
+        assert_eq!(
+            out,
+            vec![(addr(1), BlobMsg::GetChunk { blob: id, index: 0 })]
+        );
+        assert!(rx.try_recv().is_err());
+        pump(&mut stores, addr(2), out);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), blob);
+    }
+
+    #[test]
+    fn partial_downloads_are_kept_for_a_while() {
+        let mut stores = stores(2);
+        let (id, out) = stores.get_mut(&addr(1)).unwrap().put(data(3 * CHUNK_SIZE));
+        pump(&mut stores, addr(1), out);
+        let two = stores.get_mut(&addr(2)).unwrap();
+        // the manifest arrives, then we give up before any chunks do
+        let (out, _rx) = fetch(two, id, Duration::ZERO);
+        assert_eq!(out, vec![(addr(1), BlobMsg::GetManifest(id))]);
+        let manifest = stores[&addr(1)].blobs[&id].manifest.clone();
+        let two = stores.get_mut(&addr(2)).unwrap();
+        two.handle(addr(1), BlobMsg::Manifest(manifest));
+        two.poll();
+        assert!(two.downloads[&id].manifest.is_some());
+
+        two.keep_partial = Duration::ZERO;
+        two.poll();
+        assert!(two.downloads.is_empty());
+    }
+
+    #[test]
+    fn known_blobs_are_capped() {
+        let mut store = BlobStore::new(Duration::from_secs(60));
+        store.add_peer(addr(2));
+        let ids: Vec<_> = (0..MAX_KNOWN_BLOBS + 10)
+            .map(|i| BlobId::hash(&i.to_be_bytes()))
+            .collect();
+        let wanted = ids[0];
+        let (_, _rx) = fetch(&mut store, wanted, Duration::from_secs(60));
+        store.handle(addr(2), BlobMsg::Have(ids.clone()));
+        assert_eq!(store.holders.len(), MAX_KNOWN_BLOBS);
+        // the one we're fetching is never the one that makes room
+        assert!(store.holders.contains_key(&wanted));
+        assert!(store.holders.contains_key(ids.last().unwrap()));
+        store.link_down(&addr(2));
+        assert!(store.holders.is_empty());
+    }
+}
@@ -0,0 +1,251 @@
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// How many broadcasts from each node the holder had delivered (or, for a
+/// node's own entry, sent). Nodes that aren't listed are at 0.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default, Debug)]
+pub struct VectorClock(BTreeMap<SocketAddr, u64>);
+
+impl VectorClock {
+    pub fn get(&self, node: &SocketAddr) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    pub fn tick(&mut self, node: SocketAddr) {
+        *self.0.entry(node).or_insert(0) += 1;
+    }
+
+    /// Take the larger of the two counts for every node.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, count) in &other.0 {
+            let ours = self.0.entry(*node).or_insert(0);
+            *ours = (*ours).max(*count);
+        }
+    }
+
+    /// Whether a broadcast from `origin` stamped with `clock` can be delivered
+    /// by someone at `self`: it's the next one from its origin, and we have
+    /// already delivered everything its origin had when it sent it.
+    fn allows(&self, origin: &SocketAddr, clock: &VectorClock) -> bool {
+        clock.0.iter().all(|(node, count)| {
+            if node == origin {
+                *count == self.get(node) + 1
+            } else {
+                *count <= self.get(node)
+            }
+        })
+    }
+
+    fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+struct Held<M> {
+    origin: SocketAddr,
+    clock: VectorClock,
+    msg: M,
+    arrived: Instant,
+}
+
+/// Holds back broadcasts until everything that causally precedes them has
+/// been delivered. A message that is still stuck after `max_wait` (because
+/// something it depends on was lost) gets delivered anyway.
+pub struct CausalBuffer<M> {
+    me: SocketAddr,
+    clock: VectorClock,
+    held: Vec<Held<M>>,
+    max_wait: Duration,
+    /// Messages dropped because we'd already moved past them.
+    pub late: u64,
+}
+
+impl<M> CausalBuffer<M> {
+    pub fn new(me: SocketAddr, max_wait: Duration) -> Self {
+        Self {
+            me,
+            clock: Default::default(),
+            held: Default::default(),
+            max_wait,
+            late: 0,
+        }
+    }
+
+    /// The clock to send our next broadcast with.
+    pub fn stamp(&mut self) -> VectorClock {
+        self.clock.tick(self.me);
+        self.clock.clone()
+    }
+
+    /// A broadcast arrived. Returns whatever can be delivered now, in a
+    /// causally consistent order.
+    pub fn push(&mut self, origin: SocketAddr, clock: VectorClock, msg: M) -> Vec<(M, SocketAddr)> {
+        if clock.get(&origin) <= self.clock.get(&origin) {
+            self.late += 1;
+            return vec![];
+        }
+        self.held.push(Held {
+            origin,
+            clock,
+            msg,
+            arrived: Instant::now(),
+        });
+        self.drain()
+    }
+
+    /// Stop waiting on whatever messages that have been held too long depend
+    /// on, delivering them along with anything that was waiting behind them.
+    pub fn expire(&mut self) -> Vec<(M, SocketAddr)> {
+        let mut out = Vec::new();
+        loop {
+            // of the overdue ones, the one with the smallest clock is the
+            // least likely to depend on the others
+            let overdue = self
+                .held
+                .iter()
+                .enumerate()
+                .filter(|(_, h)| h.arrived.elapsed() >= self.max_wait)
+                .min_by_key(|(_, h)| h.clock.total())
+                .map(|(i, _)| i);
+            let held = match overdue {
+                Some(i) => self.held.remove(i),
+                None => break,
+            };
+            self.clock.merge(&held.clock);
+            out.push((held.msg, held.origin));
+            out.extend(self.drain());
+        }
+        out
+    }
+
+    fn drain(&mut self) -> Vec<(M, SocketAddr)> {
+        let mut ready = Vec::new();
+        while let Some(i) = self
+            .held
+            .iter()
+            .position(|h| self.clock.allows(&h.origin, &h.clock))
+        {
+            let held = self.held.remove(i);
+            self.clock.merge(&held.clock);
+            ready.push((held.msg, held.origin));
+        }
+        // anything the forced deliveries skipped past can never be delivered
+        let clock = &self.clock;
+        let before = self.held.len();
+        self.held
+            .retain(|h| h.clock.get(&h.origin) > clock.get(&h.origin));
+        self.late += (before - self.held.len()) as u64;
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    #[test]
+    fn reply_waits_for_what_it_answers() {
+        let (a, b, c) = (addr(1), addr(2), addr(3));
+        let mut nodes: Vec<_> = [a, b, c]
+            .iter()
+            .map(|n| CausalBuffer::new(*n, Duration::from_secs(60)))
+            .collect();
+
+        // a says something, b hears it and answers
+        let question = nodes[0].stamp();
+        assert_eq!(nodes[1].push(a, question.clone(), "q"), vec![("q", a)]);
+        let answer = nodes[1].stamp();
+
+        // c gets the answer first, it has to wait for the question
+        assert!(nodes[2].push(b, answer, "a").is_empty());
+        assert_eq!(nodes[2].push(a, question, "q"), vec![("q", a), ("a", b)]);
+    }
+
+    #[test]
+    fn duplicates_and_stale_messages_are_dropped() {
+        let (a, b) = (addr(1), addr(2));
+        let mut sender = CausalBuffer::<u32>::new(a, Duration::from_secs(60));
+        let mut receiver = CausalBuffer::new(b, Duration::from_secs(60));
+        let first = sender.stamp();
+        assert_eq!(receiver.push(a, first.clone(), 1).len(), 1);
+        assert!(receiver.push(a, first, 1).is_empty());
+        assert_eq!(receiver.late, 1);
+    }
+
+    #[test]
+    fn lost_dependency_is_given_up_on() {
+        let (a, b) = (addr(1), addr(2));
+        let mut sender = CausalBuffer::<u32>::new(a, Duration::from_secs(60));
+        let mut receiver = CausalBuffer::new(b, Duration::ZERO);
+        let lost = sender.stamp();
+        let second = sender.stamp();
+        assert!(receiver.push(a, second, 2).is_empty());
+        assert_eq!(receiver.expire(), vec![(2, a)]);
+        // the lost one finally shows up, too late to be delivered in order
+        assert!(receiver.push(a, lost, 1).is_empty());
+        assert_eq!(receiver.late, 1);
+    }
+
+    /// Nodes broadcast to each other in rounds, every message is delivered to
+    /// every other node but in a shuffled order. Each node must deliver a
+    /// message only after everything its sender had seen when sending it.
+    #[test]
+    fn shuffled_network_stays_causal() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let nodes: Vec<_> = (1..=5).map(addr).collect();
+        let mut buffers: Vec<_> = nodes
+            .iter()
+            .map(|n| CausalBuffer::new(*n, Duration::from_secs(60)))
+            .collect();
+        // what each node has delivered, in order, including its own sends
+        let mut logs: Vec<Vec<(SocketAddr, u64)>> = vec![vec![]; nodes.len()];
+        // what every message's sender had delivered when sending it
+        let mut deps: BTreeMap<(SocketAddr, u64), Vec<(SocketAddr, u64)>> = Default::default();
+        let mut in_flight = Vec::new();
+
+        for _ in 0..40 {
+            let s = rng.gen_range(0, nodes.len());
+            let clock = buffers[s].stamp();
+            let id = (nodes[s], clock.get(&nodes[s]));
+            deps.insert(id, logs[s].clone());
+            logs[s].push(id);
+            for r in 0..nodes.len() {
+                if r != s {
+                    in_flight.push((r, nodes[s], clock.clone(), id));
+                }
+            }
+
+            // deliver a random part of what's in flight, in random order
+            in_flight.shuffle(&mut rng);
+            let keep = in_flight.len() / 2;
+            for (r, origin, clock, id) in in_flight.split_off(keep) {
+                for (id, _) in buffers[r].push(origin, clock, id) {
+                    logs[r].push(id);
+                }
+            }
+        }
+        for (r, origin, clock, id) in in_flight {
+            for (id, _) in buffers[r].push(origin, clock, id) {
+                logs[r].push(id);
+            }
+        }
+
+        for log in &logs {
+            assert_eq!(log.len(), 40);
+            for (i, id) in log.iter().enumerate() {
+                for dep in &deps[id] {
+                    assert!(
+                        log[..i].contains(dep),
+                        "{:?} before its dependency {:?}",
+                        id,
+                        dep
+                    );
+                }
+            }
+        }
+    }
+}
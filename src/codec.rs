@@ -0,0 +1,307 @@
+use std::{fmt, io};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Turns packets into bytes and back. Which one a link uses is agreed on in
+/// the handshake.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+#[derive(Debug)]
+pub struct CodecError(String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "codec error: {}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<CodecError> for io::Error {
+    fn from(e: CodecError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+fn err(e: impl fmt::Display) -> CodecError {
+    CodecError(e.to_string())
+}
+
+/// Compact, fast, and what everything used before codecs were pluggable.
+/// Not self-describing though, both ends need the exact same types.
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(value).map_err(err)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(err)
+    }
+}
+
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(value, &mut buf).map_err(err)?;
+        Ok(buf)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        ciborium::de::from_reader(bytes).map_err(err)
+    }
+}
+
+/// Structs are written as maps with their field names, so tools don't need
+/// to know the field order.
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(value).map_err(err)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(err)
+    }
+}
+
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(err)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(err)
+    }
+}
+
+/// The codecs a node can speak, as named in handshakes.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    Cbor,
+    MessagePack,
+    Json,
+}
+
+impl CodecKind {
+    pub const ALL: [CodecKind; 4] = [
+        CodecKind::Bincode,
+        CodecKind::Cbor,
+        CodecKind::MessagePack,
+        CodecKind::Json,
+    ];
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            CodecKind::Bincode => Bincode.encode(value),
+            CodecKind::Cbor => Cbor.encode(value),
+            CodecKind::MessagePack => MessagePack.encode(value),
+            CodecKind::Json => Json.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            CodecKind::Bincode => Bincode.decode(bytes),
+            CodecKind::Cbor => Cbor.decode(bytes),
+            CodecKind::MessagePack => MessagePack.decode(bytes),
+            CodecKind::Json => Json.decode(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        blob::{BlobId, BlobMsg, Manifest},
+        causal::VectorClock,
+        dht::{Contact, DhtMsg, NodeKey},
+        filter::{BloomFilter, FilterParams},
+        linkstate::LinkStateAdvert,
+        ordering::Seq,
+        proto::{ControlMsg, GossipMsg, Operation, Packet, Payload, Priority},
+        pubsub::Interest,
+        testing::addr,
+    };
+
+    fn filter() -> BloomFilter {
+        let mut filter = BloomFilter::new(FilterParams::default());
+        filter.insert(&addr(1));
+        filter.insert(&addr(2));
+        filter
+    }
+
+    fn control() -> Vec<ControlMsg> {
+        let id = Uuid::new_v4();
+        let key = NodeKey::hash(b"key");
+        let blob = BlobId::hash(b"blob");
+        let dht = vec![
+            DhtMsg::FindNode {
+                rpc: id,
+                target: key,
+            },
+            DhtMsg::FindValue { rpc: id, key },
+            DhtMsg::Nodes {
+                rpc: id,
+                contacts: vec![Contact::new(addr(3))],
+            },
+            DhtMsg::Value {
+                rpc: id,
+                value: vec![1, 2, 3],
+            },
+            DhtMsg::Store {
+                rpc: id,
+                key,
+                value: vec![],
+            },
+            DhtMsg::Stored {
+                rpc: id,
+                kept: true,
+            },
+        ];
+        let blobs = vec![
+            BlobMsg::Have(vec![blob]),
+            BlobMsg::GetManifest(blob),
+            BlobMsg::Manifest(Manifest {
+                len: 3,
+                chunks: vec![blob],
+            }),
+            BlobMsg::GetChunk { blob, index: 0 },
+            BlobMsg::Chunk {
+                blob,
+                index: 0,
+                data: vec![0, 255],
+            },
+            BlobMsg::NotFound(blob),
+        ];
+        let mut msgs = vec![
+            ControlMsg::Ack(id),
+            ControlMsg::Gossip(GossipMsg::IHave(vec![id])),
+            ControlMsg::Gossip(GossipMsg::Graft(id)),
+            ControlMsg::Gossip(GossipMsg::Prune),
+            ControlMsg::Routes(vec![(addr(3), 2), (addr(4), 16)]),
+            ControlMsg::LinkState(LinkStateAdvert {
+                origin: addr(1),
+                seq: u64::MAX,
+                links: vec![(addr(2), 15)],
+            }),
+            ControlMsg::Ping(7),
+            ControlMsg::Pong(7),
+            ControlMsg::Heartbeat,
+            ControlMsg::Goodbye,
+            ControlMsg::Interest(Interest {
+                subscriber: addr(1),
+                seq: 9,
+                topics: vec!["news".into(), "".into()],
+            }),
+            ControlMsg::Summary {
+                since: 1_700_000_000_000,
+                have: filter(),
+            },
+            ControlMsg::Digest(vec![0, u128::MAX, id.as_u128()]),
+            ControlMsg::DigestIds {
+                buckets: vec![0, 15],
+                ids: vec![id],
+            },
+            ControlMsg::Pull(vec![id, Uuid::new_v4()]),
+        ];
+        msgs.extend(dht.into_iter().map(ControlMsg::Dht));
+        msgs.extend(blobs.into_iter().map(ControlMsg::Blob));
+        msgs
+    }
+
+    /// A packet of every kind, with every optional field filled in on some.
+    fn packets() -> Vec<Packet<String>> {
+        let mut clock = VectorClock::default();
+        clock.tick(addr(1));
+        clock.tick(addr(2));
+        clock.tick(addr(2));
+        let ops = [
+            Operation::Broadcast {
+                seen: filter(),
+                hops: 3,
+            },
+            Operation::Directed { target: addr(9) },
+            Operation::Link,
+            Operation::Topic {
+                topic: "news".into(),
+            },
+        ];
+        let mut payloads = vec![
+            Payload::Message("hello ünïcode".to_owned()),
+            Payload::Reliable {
+                id: Uuid::new_v4(),
+                msg: String::new(),
+            },
+            Payload::Request("ping".into()),
+            Payload::Reply {
+                to: Uuid::new_v4(),
+                msg: Some("pong".into()),
+            },
+            Payload::Reply {
+                to: Uuid::new_v4(),
+                msg: None,
+            },
+        ];
+        payloads.extend(control().into_iter().map(Payload::Control));
+
+        let mut packets = Vec::new();
+        for (i, payload) in payloads.into_iter().enumerate() {
+            let op = ops[i % ops.len()].clone();
+            let mut pkt = Packet::new(op, addr(1), payload);
+            pkt.priority = Priority::ALL[i % Priority::ALL.len()];
+            if i % 2 == 0 {
+                pkt.seq = Some(Seq {
+                    epoch: pkt.sent_at,
+                    n: i as u64,
+                });
+                pkt.clock = Some(clock.clone());
+            }
+            packets.push(pkt);
+        }
+        packets
+    }
+
+    #[test]
+    fn every_packet_survives_every_codec() {
+        for codec in CodecKind::ALL {
+            for pkt in packets() {
+                let bytes = codec
+                    .encode(&pkt)
+                    .unwrap_or_else(|e| panic!("{:?} can't encode {:?}: {}", codec, pkt, e));
+                let back: Packet<String> = codec
+                    .decode(&bytes)
+                    .unwrap_or_else(|e| panic!("{:?} can't decode {:?}: {}", codec, pkt, e));
+                assert_eq!(back, pkt, "{:?}", codec);
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_an_error_not_a_panic() {
+        for codec in CodecKind::ALL {
+            for bytes in [&[][..], &[0xff; 3], b"{\"id\": 1}"] {
+                assert!(codec.decode::<Packet<String>>(bytes).is_err());
+            }
+            let bytes = codec.encode(&packets()[0]).unwrap();
+            let cut = &bytes[..bytes.len() / 2];
+            assert!(codec.decode::<Packet<String>>(cut).is_err());
+        }
+    }
+}
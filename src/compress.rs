@@ -0,0 +1,94 @@
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+
+/// zstd level for frames. Low, as these are small and latency matters more
+/// than the last few bytes.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Frame compression algorithms, as named in handshakes.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    /// Cheap on CPU, decent ratio.
+    Lz4,
+    /// Better ratio, for when the link is the bottleneck.
+    Zstd,
+}
+
+impl Compression {
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+            Compression::Zstd => {
+                zstd::bulk::compress(data, ZSTD_LEVEL).expect("compressing to memory can't fail")
+            }
+        }
+    }
+
+    /// Refuses to inflate anything past `max_len`, so a tiny frame can't make
+    /// us allocate gigabytes.
+    pub fn decompress(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let too_big = || io::Error::new(io::ErrorKind::InvalidData, "compressed frame is too big");
+        match self {
+            Compression::Lz4 => {
+                let (len, _) = lz4_flex::block::uncompressed_size(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if len > max_len {
+                    return Err(too_big());
+                }
+                lz4_flex::decompress_size_prepended(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Compression::Zstd => {
+                let mut out = Vec::new();
+                zstd::stream::read::Decoder::new(data)?
+                    .take(max_len as u64 + 1)
+                    .read_to_end(&mut out)?;
+                if out.len() > max_len {
+                    return Err(too_big());
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: [Compression; 2] = [Compression::Lz4, Compression::Zstd];
+
+    #[test]
+    fn round_trips() {
+        let text = "all work and no play makes jack a dull boy. ".repeat(100);
+        for compression in BOTH {
+            for data in [&b""[..], b"x", text.as_bytes()] {
+                let packed = compression.compress(data);
+                assert_eq!(compression.decompress(&packed, data.len()).unwrap(), data);
+            }
+            assert!(compression.compress(text.as_bytes()).len() < text.len() / 10);
+        }
+    }
+
+    #[test]
+    fn bombs_are_refused() {
+        let zeros = vec![0; 1 << 20];
+        for compression in BOTH {
+            let packed = compression.compress(&zeros);
+            let err = compression
+                .decompress(&packed, zeros.len() - 1)
+                .unwrap_err();
+            assert_eq!(err.to_string(), "compressed frame is too big");
+        }
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        for compression in BOTH {
+            for data in [&b""[..], b"\xff\xff\xff\xff\xff\xff\xff\xff"] {
+                assert!(compression.decompress(data, 1024).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use uuid::Uuid;
+
+/// How far ahead of ours another node's clock may be. Packets dated further
+/// into the future than this are refused, or they'd outlive our memory of
+/// their ids and could be replayed.
+pub const MAX_SKEW: Duration = Duration::from_secs(10);
+
+/// Milliseconds since the unix epoch, what packets are timestamped with.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Packet ids we've seen lately. Packets carry the time they were sent, and
+/// anything older than the window is refused outright, so an id only has to
+/// be remembered for as long as the packet could still be accepted: `window`
+/// after it was sent, which can be up to `MAX_SKEW` after we first saw it.
+/// Memory use grows with the message rate instead of evicting ids under load.
+pub struct SeenSet {
+    ids: HashSet<Uuid>,
+    /// When each id arrived, in ms since the unix epoch, oldest first.
+    order: VecDeque<(u64, Uuid)>,
+    window: Duration,
+}
+
+impl SeenSet {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            ids: Default::default(),
+            order: Default::default(),
+            window,
+        }
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Returns false if we'd already seen `id`.
+    pub fn insert(&mut self, id: Uuid) -> bool {
+        self.insert_at(id, now_millis())
+    }
+
+    fn insert_at(&mut self, id: Uuid, now: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back((now, id));
+        true
+    }
+
+    /// Whether a packet sent at `sent_at` is too old for us to tell if it's a
+    /// duplicate, or claims to be from further in the future than clock skew
+    /// explains.
+    pub fn outside_window(&self, sent_at: u64) -> bool {
+        self.outside_window_at(sent_at, now_millis())
+    }
+
+    fn outside_window_at(&self, sent_at: u64, now: u64) -> bool {
+        now.saturating_sub(sent_at) > self.window.as_millis() as u64
+            || sent_at.saturating_sub(now) > MAX_SKEW.as_millis() as u64
+    }
+
+    /// Forget ids that anything carrying them would be refused as too old
+    /// by now, however far ahead the sender's clock was.
+    pub fn expire(&mut self) {
+        self.expire_at(now_millis())
+    }
+
+    fn expire_at(&mut self, now: u64) {
+        let keep = (self.window + MAX_SKEW).as_millis() as u64;
+        while let Some((at, id)) = self.order.front() {
+            if now.saturating_sub(*at) <= keep {
+                break;
+            }
+            self.ids.remove(id);
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_are_accepted_up_to_the_edges_of_the_window() {
+        let seen = SeenSet::new(Duration::from_secs(60));
+        let now = 1_000_000;
+        assert!(!seen.outside_window_at(now, now));
+        assert!(!seen.outside_window_at(now - 60_000, now));
+        assert!(seen.outside_window_at(now - 60_001, now));
+        assert!(seen.outside_window_at(0, now));
+    }
+
+    #[test]
+    fn packets_from_the_future_are_refused() {
+        let seen = SeenSet::new(Duration::from_secs(60));
+        let now = 1_000_000;
+        let skew = MAX_SKEW.as_millis() as u64;
+        assert!(!seen.outside_window_at(now + skew, now));
+        assert!(seen.outside_window_at(now + skew + 1, now));
+        assert!(seen.outside_window_at(u64::MAX, now));
+    }
+
+    #[test]
+    fn ids_are_remembered_for_the_window_and_the_skew() {
+        let mut seen = SeenSet::new(Duration::from_secs(60));
+        let keep = 60_000 + MAX_SKEW.as_millis() as u64;
+        let now = 1_000_000;
+        let id = Uuid::new_v4();
+        assert!(seen.insert_at(id, now));
+        assert!(!seen.insert_at(id, now));
+        seen.expire_at(now + keep);
+        assert!(seen.contains(&id));
+        seen.expire_at(now + keep + 1);
+        assert!(!seen.contains(&id));
+    }
+
+    #[test]
+    fn future_dated_replays_are_caught_after_the_window() {
+        let mut seen = SeenSet::new(Duration::from_secs(60));
+        let now = 1_000_000;
+        let sent_at = now + MAX_SKEW.as_millis() as u64;
+        let id = Uuid::new_v4();
+        assert!(!seen.outside_window_at(sent_at, now));
+        assert!(seen.insert_at(id, now));
+        // a window after it arrived, the packet's own date still lets it in
+        let later = now + 60_001;
+        seen.expire_at(later);
+        assert!(!seen.outside_window_at(sent_at, later));
+        assert!(!seen.insert_at(id, later));
+        // by the time we forget it, it's too old to get in again
+        let forgotten = sent_at + 60_001;
+        seen.expire_at(forgotten);
+        assert!(seen.outside_window_at(sent_at, forgotten));
+    }
+}
@@ -0,0 +1,641 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{sync::oneshot, time::Instant};
+use uuid::Uuid;
+
+use crate::node::NodeStopped;
+
+/// Bucket size, and how many nodes a record is stored on.
+pub const K: usize = 8;
+/// How many queries a lookup keeps in flight at once.
+const ALPHA: usize = 3;
+/// The DHT is for small records (config, presence, pointers), not bulk data.
+pub const MAX_VALUE_LEN: usize = 1024;
+/// How many records a node holds. Once full, a new record only gets in by
+/// pushing out the one whose key is farthest from ours.
+pub const MAX_RECORDS: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DhtError {
+    /// The node isn't running the DHT, see `NodeConfig::dht`.
+    Disabled,
+    /// Our own node shut down before the operation finished.
+    NodeStopped(NodeStopped),
+}
+
+impl fmt::Display for DhtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DhtError::Disabled => write!(f, "the DHT is turned off"),
+            DhtError::NodeStopped(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DhtError {}
+
+impl From<NodeStopped> for DhtError {
+    fn from(e: NodeStopped) -> Self {
+        DhtError::NodeStopped(e)
+    }
+}
+
+/// A 256 bit identifier in the DHT keyspace. Nodes get theirs by hashing
+/// their address, records by hashing their key.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeKey(pub [u8; 32]);
+
+impl NodeKey {
+    pub fn hash(bytes: &[u8]) -> Self {
+        let mut key = [0; 32];
+        key.copy_from_slice(&Sha256::digest(bytes));
+        NodeKey(key)
+    }
+
+    pub fn for_node(addr: &SocketAddr) -> Self {
+        Self::hash(&bincode::serialize(addr).expect("addresses always serialize"))
+    }
+
+    pub fn distance(&self, other: &NodeKey) -> NodeKey {
+        let mut d = [0; 32];
+        for (i, b) in d.iter_mut().enumerate() {
+            *b = self.0[i] ^ other.0[i];
+        }
+        NodeKey(d)
+    }
+
+    /// Which k-bucket `other` falls in from our point of view: the number of
+    /// leading bits we share with it. None if it's us.
+    fn bucket(&self, other: &NodeKey) -> Option<usize> {
+        let d = self.distance(other);
+        let zeros = d.0.iter().position(|b| *b != 0)?;
+        Some(zeros * 8 + d.0[zeros].leading_zeros() as usize)
+    }
+}
+
+impl fmt::Debug for NodeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0[..6] {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, "..")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Contact {
+    pub key: NodeKey,
+    pub addr: SocketAddr,
+}
+
+impl Contact {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            key: NodeKey::for_node(&addr),
+            addr,
+        }
+    }
+}
+
+/// DHT RPCs. These travel as directed packets between arbitrary nodes.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum DhtMsg {
+    FindNode {
+        rpc: Uuid,
+        target: NodeKey,
+    },
+    FindValue {
+        rpc: Uuid,
+        key: NodeKey,
+    },
+    /// Reply to either find: the closest contacts the responder knows of.
+    Nodes {
+        rpc: Uuid,
+        contacts: Vec<Contact>,
+    },
+    Value {
+        rpc: Uuid,
+        value: Vec<u8>,
+    },
+    Store {
+        rpc: Uuid,
+        key: NodeKey,
+        value: Vec<u8>,
+    },
+    /// Reply to a store, `kept` is false if the record was turned away.
+    Stored {
+        rpc: Uuid,
+        kept: bool,
+    },
+}
+
+/// What to do once a lookup has converged.
+pub enum Done {
+    /// Refreshing our own neighborhood, nobody is waiting on it.
+    Bootstrap,
+    Nodes(oneshot::Sender<Result<Vec<Contact>, DhtError>>),
+    Value(oneshot::Sender<Result<Option<Vec<u8>>, DhtError>>),
+    /// Store the value on the closest nodes, reply with how many confirmed
+    /// they kept it.
+    Store(Vec<u8>, oneshot::Sender<Result<usize, DhtError>>),
+}
+
+struct Lookup {
+    target: NodeKey,
+    find_value: bool,
+    shortlist: Vec<Contact>,
+    queried: HashSet<NodeKey>,
+    responded: HashSet<NodeKey>,
+    pending: usize,
+    done: Done,
+}
+
+struct Rpc {
+    /// The lookup or store this query is part of.
+    op: Uuid,
+    contact: Contact,
+    sent: Instant,
+}
+
+/// Stores sent out for a record, waiting to hear back from each node.
+struct PendingStore {
+    waiting: usize,
+    confirmed: usize,
+    reply: oneshot::Sender<Result<usize, DhtError>>,
+}
+
+/// DHT RPCs for the node to route to each address, usually not a neighbor.
+pub type Outbox = Vec<(SocketAddr, DhtMsg)>;
+
+/// Our corner of the Kademlia keyspace: contacts bucketed by how far their
+/// key is from ours, the records we keep, and the iterative lookups still
+/// waiting on answers. Queries time out after `timeout` and the contact is
+/// dropped from its bucket.
+pub struct Dht {
+    me: Contact,
+    buckets: Vec<VecDeque<Contact>>,
+    records: HashMap<NodeKey, Vec<u8>>,
+    lookups: HashMap<Uuid, Lookup>,
+    stores: HashMap<Uuid, PendingStore>,
+    rpcs: HashMap<Uuid, Rpc>,
+    timeout: Duration,
+}
+
+impl Dht {
+    /// Queries that go unanswered for `timeout` count as failed.
+    pub fn new(addr: SocketAddr, timeout: Duration) -> Self {
+        Self {
+            me: Contact::new(addr),
+            buckets: vec![VecDeque::new(); 256],
+            records: Default::default(),
+            lookups: Default::default(),
+            stores: Default::default(),
+            rpcs: Default::default(),
+            timeout,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|b| b.is_empty())
+    }
+
+    /// We heard from `contact`, so it's alive. Moves it to the most recently
+    /// seen end of its bucket, or adds it if there's room. Full buckets keep
+    /// their old contacts, which stay put until they stop answering.
+    pub fn observe(&mut self, contact: Contact) {
+        let bucket = match self.me.key.bucket(&contact.key) {
+            Some(b) => &mut self.buckets[b],
+            None => return,
+        };
+        if let Some(pos) = bucket.iter().position(|c| c.key == contact.key) {
+            bucket.remove(pos);
+            bucket.push_back(contact);
+        } else if bucket.len() < K {
+            bucket.push_back(contact);
+        }
+    }
+
+    fn forget(&mut self, contact: &Contact) {
+        if let Some(b) = self.me.key.bucket(&contact.key) {
+            self.buckets[b].retain(|c| c.key != contact.key);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeKey, n: usize) -> Vec<Contact> {
+        let mut all: Vec<_> = self.buckets.iter().flatten().copied().collect();
+        all.sort_by_key(|c| c.key.distance(target));
+        all.truncate(n);
+        all
+    }
+
+    pub fn get_local(&self, key: &NodeKey) -> Option<Vec<u8>> {
+        self.records.get(key).cloned()
+    }
+
+    /// Hold on to a record, if it's small enough and there's room for it.
+    fn keep(&mut self, key: NodeKey, value: Vec<u8>) -> bool {
+        if value.len() > MAX_VALUE_LEN {
+            return false;
+        }
+        if self.records.len() >= MAX_RECORDS && !self.records.contains_key(&key) {
+            let me = self.me.key;
+            let farthest = *self
+                .records
+                .keys()
+                .max_by_key(|k| k.distance(&me))
+                .expect("records are full");
+            if key.distance(&me) >= farthest.distance(&me) {
+                return false;
+            }
+            self.records.remove(&farthest);
+        }
+        self.records.insert(key, value);
+        true
+    }
+
+    /// The query `rpc`, if it's one of ours and `from` is who we asked.
+    fn answered(&mut self, rpc: &Uuid, from: SocketAddr) -> Option<Rpc> {
+        match self.rpcs.get(rpc) {
+            Some(r) if r.contact.addr == from => self.rpcs.remove(rpc),
+            _ => None,
+        }
+    }
+
+    /// One of the stores for `op` is done with.
+    fn store_done(&mut self, op: Uuid, kept: bool) {
+        if let Some(store) = self.stores.get_mut(&op) {
+            store.waiting -= 1;
+            if kept {
+                store.confirmed += 1;
+            }
+            if store.waiting == 0 {
+                let store = self.stores.remove(&op).expect("just found it");
+                let _ = store.reply.send(Ok(store.confirmed));
+            }
+        }
+    }
+
+    /// Handle an RPC from the node at `from`.
+    pub fn handle(&mut self, from: SocketAddr, msg: DhtMsg) -> Outbox {
+        self.observe(Contact::new(from));
+        match msg {
+            DhtMsg::FindNode { rpc, target } => {
+                let contacts = self.closest(&target, K);
+                vec![(from, DhtMsg::Nodes { rpc, contacts })]
+            }
+            DhtMsg::FindValue { rpc, key } => match self.records.get(&key) {
+                Some(value) => vec![(
+                    from,
+                    DhtMsg::Value {
+                        rpc,
+                        value: value.clone(),
+                    },
+                )],
+                None => {
+                    let contacts = self.closest(&key, K);
+                    vec![(from, DhtMsg::Nodes { rpc, contacts })]
+                }
+            },
+            DhtMsg::Store { rpc, key, value } => {
+                let kept = self.keep(key, value);
+                vec![(from, DhtMsg::Stored { rpc, kept })]
+            }
+            DhtMsg::Stored { rpc, kept } => {
+                if let Some(rpc) = self.answered(&rpc, from) {
+                    self.store_done(rpc.op, kept);
+                }
+                vec![]
+            }
+            DhtMsg::Nodes { rpc, contacts } => {
+                let rpc = match self.answered(&rpc, from) {
+                    Some(rpc) => rpc,
+                    None => return vec![],
+                };
+                let me = self.me.key;
+                if let Some(lookup) = self.lookups.get_mut(&rpc.op) {
+                    lookup.pending -= 1;
+                    lookup.responded.insert(rpc.contact.key);
+                    for c in contacts {
+                        // work the key out ourselves, or anyone could claim
+                        // to sit right next to whatever key they like
+                        let c = Contact::new(c.addr);
+                        if c.key != me && !lookup.shortlist.iter().any(|s| s.key == c.key) {
+                            lookup.shortlist.push(c);
+                        }
+                    }
+                }
+                self.step(rpc.op)
+            }
+            DhtMsg::Value { rpc, value } => {
+                let rpc = match self.answered(&rpc, from) {
+                    Some(rpc) => rpc,
+                    None => return vec![],
+                };
+                let lookup = match self.lookups.remove(&rpc.op) {
+                    Some(lookup) => lookup,
+                    None => return vec![],
+                };
+                match lookup.done {
+                    Done::Value(reply) => {
+                        let _ = reply.send(Ok(Some(value)));
+                        self.rpcs.retain(|_, r| r.op != rpc.op);
+                        vec![]
+                    }
+                    done => {
+                        // we only asked it for nodes, count it as an answer
+                        // without any
+                        println!("dropping a DHT value from {} that nobody asked for", from);
+                        let mut lookup = Lookup { done, ..lookup };
+                        lookup.pending -= 1;
+                        lookup.responded.insert(rpc.contact.key);
+                        self.lookups.insert(rpc.op, lookup);
+                        self.step(rpc.op)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start an iterative lookup of `target`.
+    pub fn lookup(&mut self, target: NodeKey, done: Done) -> Outbox {
+        let id = Uuid::new_v4();
+        let find_value = matches!(done, Done::Value(_));
+        self.lookups.insert(
+            id,
+            Lookup {
+                target,
+                find_value,
+                shortlist: self.closest(&target, K),
+                queried: Default::default(),
+                responded: Default::default(),
+                pending: 0,
+                done,
+            },
+        );
+        self.step(id)
+    }
+
+    /// Query the closest contacts we haven't asked yet, or wrap the lookup
+    /// up if there's nobody left to ask.
+    fn step(&mut self, id: Uuid) -> Outbox {
+        let lookup = match self.lookups.get_mut(&id) {
+            Some(l) => l,
+            None => return vec![],
+        };
+        let target = lookup.target;
+        lookup.shortlist.sort_by_key(|c| c.key.distance(&target));
+
+        let mut out = Vec::new();
+        let candidates: Vec<_> = lookup
+            .shortlist
+            .iter()
+            .take(K)
+            .filter(|c| !lookup.queried.contains(&c.key))
+            .take(ALPHA.saturating_sub(lookup.pending))
+            .copied()
+            .collect();
+        for contact in candidates {
+            let rpc = Uuid::new_v4();
+            let msg = if lookup.find_value {
+                DhtMsg::FindValue { rpc, key: target }
+            } else {
+                DhtMsg::FindNode { rpc, target }
+            };
+            lookup.queried.insert(contact.key);
+            lookup.pending += 1;
+            self.rpcs.insert(
+                rpc,
+                Rpc {
+                    op: id,
+                    contact,
+                    sent: Instant::now(),
+                },
+            );
+            out.push((contact.addr, msg));
+        }
+
+        if lookup.pending == 0 {
+            out.extend(self.finish(id));
+        }
+        out
+    }
+
+    fn finish(&mut self, id: Uuid) -> Outbox {
+        let lookup = match self.lookups.remove(&id) {
+            Some(l) => l,
+            None => return vec![],
+        };
+        let closest: Vec<_> = lookup
+            .shortlist
+            .iter()
+            .filter(|c| lookup.responded.contains(&c.key))
+            .take(K)
+            .copied()
+            .collect();
+        match lookup.done {
+            Done::Bootstrap => vec![],
+            Done::Nodes(reply) => {
+                let _ = reply.send(Ok(closest));
+                vec![]
+            }
+            Done::Value(reply) => {
+                let _ = reply.send(Ok(None));
+                vec![]
+            }
+            Done::Store(value, reply) => {
+                let key = lookup.target;
+                // we hold a copy too if we're among the closest nodes
+                let mut confirmed = 0;
+                let ours = closest.len() < K
+                    || closest
+                        .last()
+                        .is_none_or(|far| self.me.key.distance(&key) < far.key.distance(&key));
+                if ours && self.keep(key, value.clone()) {
+                    confirmed += 1;
+                }
+                if closest.is_empty() {
+                    let _ = reply.send(Ok(confirmed));
+                    return vec![];
+                }
+                let op = Uuid::new_v4();
+                let mut out = Vec::new();
+                for contact in closest {
+                    let rpc = Uuid::new_v4();
+                    let sent = Instant::now();
+                    self.rpcs.insert(rpc, Rpc { op, contact, sent });
+                    let value = value.clone();
+                    out.push((contact.addr, DhtMsg::Store { rpc, key, value }));
+                }
+                let store = PendingStore {
+                    waiting: out.len(),
+                    confirmed,
+                    reply,
+                };
+                self.stores.insert(op, store);
+                out
+            }
+        }
+    }
+
+    /// Fail queries that have been outstanding too long, dropping the contacts
+    /// that didn't answer, and move their lookups along.
+    pub fn expire(&mut self) -> Outbox {
+        let timeout = self.timeout;
+        let expired: Vec<_> = self
+            .rpcs
+            .iter()
+            .filter(|(_, r)| r.sent.elapsed() > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut out = Vec::new();
+        for id in expired {
+            let rpc = self.rpcs.remove(&id).expect("just found it");
+            self.forget(&rpc.contact);
+            if let Some(lookup) = self.lookups.get_mut(&rpc.op) {
+                lookup.pending -= 1;
+                lookup.shortlist.retain(|c| c.key != rpc.contact.key);
+                out.extend(self.step(rpc.op));
+            } else {
+                self.store_done(rpc.op, false);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    /// A DHT at port 1 that knows about `ports`.
+    fn dht(ports: &[u16]) -> Dht {
+        let mut dht = Dht::new(addr(1), Duration::from_secs(60));
+        for port in ports {
+            dht.observe(Contact::new(addr(*port)));
+        }
+        dht
+    }
+
+    fn rpc_to(out: &Outbox, to: SocketAddr) -> Uuid {
+        out.iter()
+            .find_map(|(a, msg)| match msg {
+                DhtMsg::FindNode { rpc, .. }
+                | DhtMsg::FindValue { rpc, .. }
+                | DhtMsg::Store { rpc, .. }
+                    if *a == to =>
+                {
+                    Some(*rpc)
+                }
+                _ => None,
+            })
+            .expect("a query for that node")
+    }
+
+    #[test]
+    fn a_value_nobody_asked_for_is_dropped() {
+        let mut dht = dht(&[2]);
+        let (tx, mut rx) = oneshot::channel();
+        let out = dht.lookup(NodeKey::hash(b"x"), Done::Nodes(tx));
+        let rpc = rpc_to(&out, addr(2));
+        let value = b"surprise".to_vec();
+        assert!(dht.handle(addr(2), DhtMsg::Value { rpc, value }).is_empty());
+        // it still counts as an answer, so the lookup finishes
+        let found = rx.try_recv().expect("lookup finished");
+        assert_eq!(found, Ok(vec![Contact::new(addr(2))]));
+    }
+
+    #[test]
+    fn contacts_get_keys_we_work_out_ourselves() {
+        let mut dht = dht(&[2]);
+        let target = NodeKey::hash(b"x");
+        let (tx, mut rx) = oneshot::channel();
+        let out = dht.lookup(target, Done::Nodes(tx));
+        let rpc = rpc_to(&out, addr(2));
+        // 9 claims to sit right on the target
+        let contacts = vec![Contact {
+            key: target,
+            addr: addr(9),
+        }];
+        let out = dht.handle(addr(2), DhtMsg::Nodes { rpc, contacts });
+        let rpc = rpc_to(&out, addr(9));
+        assert_eq!(dht.rpcs[&rpc].contact, Contact::new(addr(9)));
+        let contacts = vec![];
+        dht.handle(addr(9), DhtMsg::Nodes { rpc, contacts });
+        let found = rx.try_recv().expect("lookup finished").unwrap();
+        assert!(found.iter().all(|c| c.key != target));
+    }
+
+    #[test]
+    fn replies_only_count_from_the_node_that_was_asked() {
+        let mut dht = dht(&[2]);
+        let (tx, mut rx) = oneshot::channel();
+        let out = dht.lookup(NodeKey::hash(b"x"), Done::Value(tx));
+        let rpc = rpc_to(&out, addr(2));
+        let value = b"forged".to_vec();
+        dht.handle(addr(3), DhtMsg::Value { rpc, value });
+        assert!(rx.try_recv().is_err());
+        let value = b"real".to_vec();
+        dht.handle(addr(2), DhtMsg::Value { rpc, value });
+        assert_eq!(rx.try_recv(), Ok(Ok(Some(b"real".to_vec()))));
+    }
+
+    #[tokio::test]
+    async fn stores_count_only_confirmed_copies() {
+        tokio::time::pause();
+        let mut dht = dht(&[2, 3, 4]);
+        let key = NodeKey::hash(b"k");
+        let (tx, mut rx) = oneshot::channel();
+        let out = dht.lookup(key, Done::Store(b"v".to_vec(), tx));
+        let mut stores = Vec::new();
+        for port in [2, 3, 4] {
+            let rpc = rpc_to(&out, addr(port));
+            let contacts = vec![];
+            stores.extend(dht.handle(addr(port), DhtMsg::Nodes { rpc, contacts }));
+        }
+        assert_eq!(stores.len(), 3);
+        // we're among the closest, so we hold a copy too
+        assert_eq!(dht.get_local(&key), Some(b"v".to_vec()));
+
+        let rpc = rpc_to(&stores, addr(2));
+        dht.handle(addr(2), DhtMsg::Stored { rpc, kept: true });
+        let rpc = rpc_to(&stores, addr(3));
+        dht.handle(addr(3), DhtMsg::Stored { rpc, kept: false });
+        assert!(rx.try_recv().is_err());
+        // 4 never answers
+        tokio::time::advance(Duration::from_secs(61)).await;
+        dht.expire();
+        assert_eq!(rx.try_recv(), Ok(Ok(2)));
+    }
+
+    #[test]
+    fn records_are_capped_keeping_the_closest() {
+        let mut dht = dht(&[]);
+        let me = dht.me.key;
+        let keys: Vec<_> = (0..MAX_RECORDS + 100)
+            .map(|i| NodeKey::hash(&i.to_be_bytes()))
+            .collect();
+        for key in &keys {
+            dht.keep(*key, vec![1]);
+        }
+        assert_eq!(dht.records.len(), MAX_RECORDS);
+        let farthest_kept = dht.records.keys().map(|k| k.distance(&me)).max().unwrap();
+        let closest_dropped = keys
+            .iter()
+            .filter(|k| !dht.records.contains_key(k))
+            .map(|k| k.distance(&me))
+            .min()
+            .unwrap();
+        assert!(farthest_kept < closest_dropped);
+        // values we already hold can still be updated when full
+        dht.keep(keys[0], vec![2]);
+        assert!(!dht.keep(NodeKey::hash(b"big"), vec![0; MAX_VALUE_LEN + 1]));
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+
+/// Sizing parameters for a `BloomFilter`. Every node in a network has to agree
+/// on these for broadcasts, since the filter is shipped along with each packet.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FilterParams {
+    pub bits: usize,
+    pub hashes: u8,
+}
+
+impl FilterParams {
+    /// Size a filter so that after `items` insertions the false positive rate
+    /// stays at or below `fp_rate` (standard m = -n ln p / ln(2)^2 sizing).
+    pub fn with_budget(items: usize, fp_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let fp_rate = fp_rate.clamp(f64::MIN_POSITIVE, 1.0);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(items * fp_rate.ln()) / (ln2 * ln2)).ceil() as usize;
+        // round up to whole words, as that's what we put on the wire anyway
+        let bits = bits.max(1).div_ceil(64) * 64;
+        let hashes = ((bits as f64 / items) * ln2).round().clamp(1.0, 255.0) as u8;
+        Self { bits, hashes }
+    }
+}
+
+impl Default for FilterParams {
+    /// Enough for a path of 32 hops at a 1% false positive rate (320 bits).
+    /// That's 49 bytes with bincode no matter the path length, where a
+    /// `HashSet<SocketAddr>` costs 8 bytes plus 10 per IPv4 hop (22 per IPv6).
+    fn default() -> Self {
+        Self::with_budget(32, 0.01)
+    }
+}
+
+/// A fixed size bloom filter. Items are hashed from their bincode encoding, so
+/// the bits are the same no matter which platform set them.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BloomFilter {
+    words: Vec<u64>,
+    hashes: u8,
+}
+
+impl BloomFilter {
+    pub fn new(params: FilterParams) -> Self {
+        Self {
+            words: vec![0; params.bits.div_ceil(64)],
+            hashes: params.hashes,
+        }
+    }
+
+    pub fn insert<T: Serialize>(&mut self, item: &T) {
+        for bit in self.bits_for(item) {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns true if `item` may have been inserted. False positives are
+    /// possible, false negatives are not.
+    pub fn contains<T: Serialize>(&self, item: &T) -> bool {
+        // filters from the network could have no bits or no hashes, nothing
+        // is in those
+        !self.is_degenerate()
+            && self
+                .bits_for(item)
+                .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Whether this filter is shaped the way `params` would make it. Filters
+    /// that came off the network don't have to be.
+    pub fn fits(&self, params: FilterParams) -> bool {
+        self.words.len() == params.bits.div_ceil(64) && self.hashes == params.hashes
+    }
+
+    /// The chance that `contains` is true for something that was never
+    /// inserted, estimated from how many bits are set.
+    pub fn false_positive_rate(&self) -> f64 {
+        if self.is_degenerate() {
+            return 0.0;
+        }
+        let ones: u32 = self.words.iter().map(|w| w.count_ones()).sum();
+        let nbits = (self.words.len() * 64) as f64;
+        (ones as f64 / nbits).powi(self.hashes as i32)
+    }
+
+    fn is_degenerate(&self) -> bool {
+        self.words.is_empty() || self.hashes == 0
+    }
+
+    fn bits_for<T: Serialize>(&self, item: &T) -> impl Iterator<Item = usize> {
+        let bytes = bincode::serialize(item).expect("filter items must be serializable");
+        // Kirsch-Mitzenmacher double hashing: k indices from two base hashes
+        let h1 = fnv1a(&bytes, 0xcbf2_9ce4_8422_2325);
+        let h2 = fnv1a(&bytes, 0x6c62_272e_07bb_0142) | 1;
+        let nbits = (self.words.len() * 64) as u64;
+        // no bits, no indices (and no dividing by zero)
+        let hashes = if nbits == 0 { 0 } else { self.hashes as u64 };
+        (0..hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+}
+
+fn fnv1a(bytes: &[u8], seed: u64) -> u64 {
+    bytes.iter().fold(seed, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+    use std::{collections::HashSet, net::SocketAddr};
+
+    #[test]
+    fn no_false_negatives() {
+        let mut filter = BloomFilter::new(FilterParams::default());
+        for port in 0..32 {
+            filter.insert(&addr(port));
+        }
+        assert!((0..32).all(|port| filter.contains(&addr(port))));
+    }
+
+    #[test]
+    fn false_positives_stay_within_budget() {
+        let params = FilterParams::with_budget(32, 0.01);
+        let mut filter = BloomFilter::new(params);
+        for port in 0..32 {
+            filter.insert(&addr(port));
+        }
+        let trials = 10_000;
+        let hits = (1000..1000 + trials)
+            .filter(|port| filter.contains(&addr(*port)))
+            .count();
+        let rate = hits as f64 / trials as f64;
+        assert!(rate < 0.02, "false positive rate {}", rate);
+        assert!(filter.false_positive_rate() < 0.02);
+    }
+
+    #[test]
+    fn estimate_grows_past_the_budget() {
+        let mut filter = BloomFilter::new(FilterParams::with_budget(8, 0.01));
+        assert_eq!(filter.false_positive_rate(), 0.0);
+        for port in 0..8 {
+            filter.insert(&addr(port));
+        }
+        let at_budget = filter.false_positive_rate();
+        for port in 8..64 {
+            filter.insert(&addr(port));
+        }
+        assert!(filter.false_positive_rate() > at_budget * 10.0);
+    }
+
+    /// Filters are deserialized straight off the wire, whatever shape they
+    /// have has to be safe to use.
+    #[test]
+    fn malformed_filters_are_harmless() {
+        for (words, hashes) in [(vec![], 7), (vec![], 0), (vec![u64::MAX], 0)] {
+            let mut filter = BloomFilter { words, hashes };
+            assert!(!filter.contains(&addr(1)));
+            filter.insert(&addr(1));
+            assert!(!filter.contains(&addr(2)));
+            assert_eq!(filter.false_positive_rate(), 0.0);
+            assert!(!filter.fits(FilterParams::default()));
+        }
+        assert!(BloomFilter::new(FilterParams::default()).fits(FilterParams::default()));
+    }
+
+    /// A broadcast's path on the wire: the default filter stays the same
+    /// size however long the path gets, and is smaller than the old address
+    /// set from 5 hops on.
+    #[test]
+    fn wire_size_is_bounded_unlike_an_address_set() {
+        let params = FilterParams::default();
+        // length prefix, the words, and the hash count
+        let bound = 8 + params.bits as u64 / 8 + 1;
+        assert_eq!(bound, 49);
+        for hops in [1, 2, 4, 5, 8, 16, 32, 64, 256] {
+            let set: HashSet<SocketAddr> = (0..hops).map(addr).collect();
+            let mut filter = BloomFilter::new(params);
+            for port in 0..hops {
+                filter.insert(&addr(port));
+            }
+            let set_len = bincode::serialized_size(&set).unwrap();
+            let filter_len = bincode::serialized_size(&filter).unwrap();
+            assert_eq!(set_len, 8 + 10 * hops as u64);
+            assert_eq!(filter_len, bound, "{} hops", hops);
+            assert_eq!(filter_len < set_len, hops >= 5, "{} hops", hops);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::proto::Packet;
+
+/// How a node relays broadcasts to its neighbors.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum BroadcastStrategy {
+    /// Send every new message to every peer. Simple and robust, but costs a
+    /// packet per edge in the mesh.
+    #[default]
+    Flood,
+    /// Epidemic broadcast trees: push eagerly along a spanning tree and only
+    /// announce message ids (IHAVE) over the remaining links, grafting them
+    /// back in when the tree breaks.
+    Plumtree,
+}
+
+const CACHE_CAPACITY: usize = 256;
+
+struct Missing {
+    since: Instant,
+    announcers: VecDeque<SocketAddr>,
+}
+
+/// The per-node state for Plumtree. This only tracks who is in which set and
+/// what we're missing, the node does the actual sending.
+pub struct Plumtree<M> {
+    eager: HashSet<SocketAddr>,
+    lazy: HashSet<SocketAddr>,
+    /// Recently received messages, so we can answer grafts.
+    cache: LruCache<Uuid, Packet<M>>,
+    missing: HashMap<Uuid, Missing>,
+    graft_timeout: Duration,
+}
+
+impl<M: Clone> Plumtree<M> {
+    pub fn new(graft_timeout: Duration) -> Self {
+        Self {
+            eager: Default::default(),
+            lazy: Default::default(),
+            cache: LruCache::new(CACHE_CAPACITY),
+            missing: Default::default(),
+            graft_timeout,
+        }
+    }
+
+    /// New links start out eager, the tree prunes itself from there.
+    pub fn add_peer(&mut self, peer: SocketAddr) {
+        self.lazy.remove(&peer);
+        self.eager.insert(peer);
+    }
+
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.eager.remove(peer);
+        self.lazy.remove(peer);
+        for missing in self.missing.values_mut() {
+            missing.announcers.retain(|a| a != peer);
+        }
+    }
+
+    pub fn eager_peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.eager.iter()
+    }
+
+    pub fn lazy_peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.lazy.iter()
+    }
+
+    /// A message we hadn't seen arrived from `from`.
+    pub fn received(&mut self, pkt: &Packet<M>, from: Option<SocketAddr>) {
+        self.missing.remove(&pkt.id);
+        self.cache.put(pkt.id, pkt.clone());
+        if let Some(from) = from {
+            self.add_peer(from);
+        }
+    }
+
+    /// `from` sent us a message we already had, so the link is redundant.
+    /// Returns false if it already was, i.e. there's nothing to tell `from`.
+    pub fn prune(&mut self, from: SocketAddr) -> bool {
+        let was_eager = self.eager.remove(&from);
+        if was_eager {
+            self.lazy.insert(from);
+        }
+        was_eager
+    }
+
+    /// `from` has messages we have not seen (yet).
+    pub fn ihave(&mut self, id: Uuid, from: SocketAddr) {
+        self.missing
+            .entry(id)
+            .or_insert_with(|| Missing {
+                since: Instant::now(),
+                announcers: VecDeque::new(),
+            })
+            .announcers
+            .push_back(from);
+    }
+
+    /// `from` wants a message, returns it if we still have it around.
+    pub fn graft(&mut self, id: &Uuid, from: SocketAddr) -> Option<Packet<M>> {
+        self.add_peer(from);
+        self.cache.get(id).cloned()
+    }
+
+    /// Messages that were announced but didn't show up in time, along with
+    /// the peer to graft each one from. The next announcer gets its turn
+    /// after another timeout.
+    pub fn expired(&mut self) -> Vec<(Uuid, SocketAddr)> {
+        let now = Instant::now();
+        let mut grafts = Vec::new();
+        for (id, missing) in self.missing.iter_mut() {
+            if now.duration_since(missing.since) < self.graft_timeout {
+                continue;
+            }
+            if let Some(peer) = missing.announcers.pop_front() {
+                grafts.push((*id, peer));
+                missing.since = now;
+            }
+        }
+        self.missing.retain(|_, m| !m.announcers.is_empty());
+        for (_, peer) in &grafts {
+            self.add_peer(*peer);
+        }
+        grafts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::BloomFilter, proto::Operation, proto::Payload, testing::addr};
+
+    fn packet() -> Packet<u32> {
+        let seen = BloomFilter::new(Default::default());
+        let op = Operation::Broadcast { seen, hops: 0 };
+        Packet::new(op, addr(1), Payload::Message(7))
+    }
+
+    #[test]
+    fn only_eager_peers_are_pruned() {
+        let mut tree = Plumtree::<u32>::new(Duration::from_secs(1));
+        tree.add_peer(addr(2));
+        assert!(tree.prune(addr(2)));
+        assert!(!tree.prune(addr(2)));
+        assert_eq!(tree.lazy_peers().collect::<Vec<_>>(), vec![&addr(2)]);
+        assert_eq!(tree.eager_peers().count(), 0);
+        // never a peer at all
+        assert!(!tree.prune(addr(3)));
+        assert_eq!(tree.lazy_peers().count(), 1);
+    }
+
+    #[test]
+    fn receiving_from_a_lazy_peer_makes_it_eager() {
+        let mut tree = Plumtree::new(Duration::from_secs(1));
+        tree.add_peer(addr(2));
+        tree.prune(addr(2));
+        tree.received(&packet(), Some(addr(2)));
+        assert_eq!(tree.eager_peers().collect::<Vec<_>>(), vec![&addr(2)]);
+        assert_eq!(tree.lazy_peers().count(), 0);
+    }
+
+    #[test]
+    fn missing_messages_are_grafted_from_each_announcer_in_turn() {
+        let mut tree = Plumtree::<u32>::new(Duration::ZERO);
+        let id = Uuid::new_v4();
+        tree.ihave(id, addr(2));
+        tree.ihave(id, addr(3));
+        assert_eq!(tree.expired(), vec![(id, addr(2))]);
+        assert_eq!(tree.expired(), vec![(id, addr(3))]);
+        assert!(tree.expired().is_empty());
+        // whoever we grafted from is pushing to us again
+        assert_eq!(tree.eager_peers().count(), 2);
+    }
+
+    #[test]
+    fn messages_that_show_up_are_not_grafted() {
+        let mut tree = Plumtree::new(Duration::ZERO);
+        let pkt = packet();
+        tree.ihave(pkt.id, addr(2));
+        tree.received(&pkt, Some(addr(3)));
+        assert!(tree.expired().is_empty());
+        assert_eq!(tree.graft(&pkt.id, addr(2)), Some(pkt));
+    }
+}
@@ -0,0 +1,201 @@
+use std::{fmt, io, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    codec::CodecKind, compress::Compression, gossip::BroadcastStrategy, routing::RoutingMode,
+};
+
+/// Every connection starts with this, so we can tell a peer from something
+/// else that happened to connect to our port.
+pub const MAGIC: [u8; 4] = *b"POE\0";
+/// Bump this whenever the wire format changes (including `Feature`). Nodes
+/// only talk to peers on the same version.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Hellos are small, anything bigger than this is garbage.
+const MAX_HELLO_LEN: u64 = 64 * 1024;
+
+/// Things a node can do or is configured for, announced in its hello.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Feature {
+    Broadcast(BroadcastStrategy),
+    Routing(RoutingMode),
+    Dht,
+    Causal,
+    /// A codec the node can use for packets. Listed in order of preference.
+    Codec(CodecKind),
+    /// A frame compression the node can use, also in order of preference.
+    Compression(Compression),
+}
+
+impl Feature {
+    /// Whether both ends of a link have to agree on this one.
+    fn must_match(&self) -> bool {
+        !matches!(
+            self,
+            Feature::Dht | Feature::Codec(_) | Feature::Compression(_)
+        )
+    }
+}
+
+/// The first thing each side of a new connection sends.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Hello {
+    /// The sender's node id (the address it listens on).
+    pub node: SocketAddr,
+    /// When the sender started, in ms since the unix epoch. Tells a link to
+    /// a node that has restarted apart from one left over from before.
+    pub epoch: u64,
+    pub features: Vec<Feature>,
+}
+
+impl Hello {
+    pub fn has(&self, feature: &Feature) -> bool {
+        self.features.contains(feature)
+    }
+
+    /// The most preferred of the features picked out by `option` that both
+    /// ends have, going by the preferences of whichever node has the lower id
+    /// so both ends come to the same answer.
+    fn negotiate<T>(&self, theirs: &Hello, option: impl Fn(&Feature) -> Option<T>) -> Option<T> {
+        let (first, second) = if self.node < theirs.node {
+            (self, theirs)
+        } else {
+            (theirs, self)
+        };
+        first
+            .features
+            .iter()
+            .find(|f| option(f).is_some() && second.has(f))
+            .and_then(option)
+    }
+
+    /// The codec to use on a link between us and `theirs`.
+    pub fn codec(&self, theirs: &Hello) -> Option<CodecKind> {
+        self.negotiate(theirs, |f| match f {
+            Feature::Codec(c) => Some(*c),
+            _ => None,
+        })
+    }
+
+    /// The compression to use on a link between us and `theirs`, if any.
+    pub fn compression(&self, theirs: &Hello) -> Option<Compression> {
+        self.negotiate(theirs, |f| match f {
+            Feature::Compression(c) => Some(*c),
+            _ => None,
+        })
+    }
+
+    /// Can we work with a peer that sent `theirs`?
+    pub fn check(&self, theirs: &Hello) -> Result<(), HandshakeError> {
+        if theirs.node == self.node {
+            return Err(HandshakeError::Incompatible(
+                "connected to ourselves".into(),
+            ));
+        }
+        let mismatch = self
+            .features
+            .iter()
+            .filter(|f| f.must_match())
+            .find(|f| !theirs.has(f))
+            .or_else(|| {
+                theirs
+                    .features
+                    .iter()
+                    .filter(|f| f.must_match())
+                    .find(|f| !self.has(f))
+            });
+        if self.codec(theirs).is_none() {
+            return Err(HandshakeError::Incompatible("no codec in common".into()));
+        }
+        match mismatch {
+            Some(f) => Err(HandshakeError::Incompatible(format!(
+                "{:?} isn't used on both ends",
+                f
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    /// The other end isn't speaking our protocol at all.
+    BadMagic,
+    Version {
+        ours: u16,
+        theirs: u16,
+    },
+    /// Right magic and version, but the hello itself didn't parse.
+    Malformed,
+    Incompatible(String),
+    Timeout,
+    /// We've banned the node on the other end.
+    Banned,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "handshake failed: {}", e),
+            HandshakeError::BadMagic => write!(f, "peer isn't speaking our protocol"),
+            HandshakeError::Version { ours, theirs } => write!(
+                f,
+                "peer speaks protocol version {}, we speak {}",
+                theirs, ours
+            ),
+            HandshakeError::Malformed => write!(f, "peer sent a malformed hello"),
+            HandshakeError::Incompatible(why) => write!(f, "incompatible peer: {}", why),
+            HandshakeError::Timeout => write!(f, "peer didn't say hello in time"),
+            HandshakeError::Banned => write!(f, "peer is banned"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+/// Swap hellos over a fresh connection. Both sides send theirs straight
+/// away, so it doesn't matter who connected to whom. The magic and version
+/// go first on their own, so a mismatch is still reported properly if a
+/// later version changes the layout of the rest.
+pub async fn handshake(stream: &mut TcpStream, ours: &Hello) -> Result<Hello, HandshakeError> {
+    let body = bincode::serialize(ours).expect("hellos always serialize");
+    stream.write_all(&MAGIC).await?;
+    stream.write_u16(PROTOCOL_VERSION).await?;
+    stream.write_u64(body.len() as u64).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic).await?;
+    if magic != MAGIC {
+        return Err(HandshakeError::BadMagic);
+    }
+    let version = stream.read_u16().await?;
+    if version != PROTOCOL_VERSION {
+        return Err(HandshakeError::Version {
+            ours: PROTOCOL_VERSION,
+            theirs: version,
+        });
+    }
+    let len = stream.read_u64().await?;
+    if len > MAX_HELLO_LEN {
+        return Err(HandshakeError::Malformed);
+    }
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    let theirs: Hello = bincode::deserialize(&buf).map_err(|_| HandshakeError::Malformed)?;
+    ours.check(&theirs)?;
+    Ok(theirs)
+}
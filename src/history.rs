@@ -0,0 +1,230 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::{
+    filter::{BloomFilter, FilterParams},
+    proto::Packet,
+};
+
+/// How many buckets anti-entropy digests split the id space into.
+pub const DIGEST_BUCKETS: usize = 16;
+
+/// False positive rate for history summaries. A false positive means a
+/// neighbor skips replaying a message we actually missed, so keep it low.
+const SUMMARY_FP_RATE: f64 = 0.001;
+
+/// Recent broadcasts, kept around so they can be replayed to neighbors that
+/// were offline when they went through.
+pub struct History<M> {
+    packets: VecDeque<(Instant, Packet<M>)>,
+    max_len: usize,
+    max_age: Duration,
+}
+
+impl<M: Clone> History<M> {
+    pub fn new(max_len: usize, max_age: Duration) -> Self {
+        Self {
+            packets: Default::default(),
+            max_len,
+            max_age,
+        }
+    }
+
+    pub fn record(&mut self, pkt: Packet<M>) {
+        if self.max_len == 0 {
+            return;
+        }
+        if self.packets.len() == self.max_len {
+            self.packets.pop_front();
+        }
+        self.packets.push_back((Instant::now(), pkt));
+    }
+
+    /// Which of the recent broadcasts we have, to send to a neighbor when we
+    /// (re)connect so it can fill us in on the rest.
+    pub fn summary(&mut self) -> BloomFilter {
+        self.expire();
+        let mut filter = BloomFilter::new(FilterParams::with_budget(
+            self.packets.len(),
+            SUMMARY_FP_RATE,
+        ));
+        for (_, pkt) in &self.packets {
+            filter.insert(&pkt.id);
+        }
+        filter
+    }
+
+    /// The packets sent after `since` that a neighbor with `summary` hasn't
+    /// got, oldest first.
+    pub fn missing(&mut self, summary: &BloomFilter, since: u64) -> Vec<Packet<M>> {
+        self.expire();
+        self.packets
+            .iter()
+            .filter(|(_, pkt)| pkt.sent_at >= since && !summary.contains(&pkt.id))
+            .map(|(_, pkt)| pkt.clone())
+            .collect()
+    }
+
+    /// Ids of everything we have, for comparing notes with a neighbor.
+    pub fn ids(&mut self) -> Vec<Uuid> {
+        self.expire();
+        self.packets.iter().map(|(_, pkt)| pkt.id).collect()
+    }
+
+    /// A compact summary of what we have for anti-entropy: the ids in each
+    /// bucket xored together. Neighbors with the same broadcasts have the
+    /// same digest, whatever order they got them in.
+    pub fn digest(&mut self) -> Vec<u128> {
+        let mut digest = vec![0; DIGEST_BUCKETS];
+        for id in self.ids() {
+            digest[bucket(&id)] ^= id.as_u128();
+        }
+        digest
+    }
+
+    /// The buckets where a neighbor's digest doesn't match ours.
+    pub fn differing(&mut self, theirs: &[u128]) -> Vec<u8> {
+        let ours = self.digest();
+        (0..DIGEST_BUCKETS)
+            .filter(|b| theirs.get(*b) != Some(&ours[*b]))
+            .map(|b| b as u8)
+            .collect()
+    }
+
+    /// Ids of what we have in `buckets`.
+    pub fn ids_in(&mut self, buckets: &[u8]) -> Vec<Uuid> {
+        self.ids()
+            .into_iter()
+            .filter(|id| buckets.contains(&(bucket(id) as u8)))
+            .collect()
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Packet<M>> {
+        self.packets
+            .iter()
+            .find(|(_, pkt)| pkt.id == *id)
+            .map(|(_, pkt)| pkt.clone())
+    }
+
+    fn expire(&mut self) {
+        while let Some((at, _)) = self.packets.front() {
+            if at.elapsed() <= self.max_age {
+                break;
+            }
+            self.packets.pop_front();
+        }
+    }
+}
+
+fn bucket(id: &Uuid) -> usize {
+    id.as_bytes()[0] as usize % DIGEST_BUCKETS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{Operation, Payload};
+    use std::net::SocketAddr;
+
+    fn packet(sent_at: u64) -> Packet<u32> {
+        let seen = BloomFilter::new(Default::default());
+        let op = Operation::Broadcast { seen, hops: 0 };
+        let mut pkt = Packet::new(
+            op,
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+            Payload::Message(7),
+        );
+        pkt.sent_at = sent_at;
+        pkt
+    }
+
+    #[test]
+    fn only_what_the_neighbor_lacks_is_replayed() {
+        let mut ours = History::new(10, Duration::from_secs(60));
+        let mut theirs = History::new(10, Duration::from_secs(60));
+        let pkts: Vec<_> = (0..4).map(|i| packet(1000 + i)).collect();
+        for pkt in &pkts {
+            ours.record(pkt.clone());
+        }
+        theirs.record(pkts[1].clone());
+        let missing = ours.missing(&theirs.summary(), 0);
+        assert_eq!(
+            missing,
+            vec![pkts[0].clone(), pkts[2].clone(), pkts[3].clone()]
+        );
+    }
+
+    #[test]
+    fn nothing_from_before_the_neighbor_went_away_is_replayed() {
+        let mut ours = History::new(10, Duration::from_secs(60));
+        let pkts: Vec<_> = (0..4).map(|i| packet(1000 + i)).collect();
+        for pkt in &pkts {
+            ours.record(pkt.clone());
+        }
+        let fresh = History::<u32>::new(10, Duration::from_secs(60)).summary();
+        assert_eq!(ours.missing(&fresh, 1002), pkts[2..].to_vec());
+        assert!(ours.missing(&fresh, 2000).is_empty());
+    }
+
+    #[tokio::test]
+    async fn history_is_bounded_in_length_and_age() {
+        tokio::time::pause();
+        let mut history = History::new(2, Duration::from_secs(60));
+        let pkts: Vec<_> = (0..3).map(packet).collect();
+        for pkt in &pkts {
+            history.record(pkt.clone());
+        }
+        assert_eq!(history.ids(), vec![pkts[1].id, pkts[2].id]);
+        assert!(history.get(&pkts[0].id).is_none());
+
+        let mut history = History::new(2, Duration::ZERO);
+        history.record(packet(0));
+        tokio::time::advance(Duration::from_millis(5)).await;
+        assert!(history.ids().is_empty());
+
+        let mut off = History::new(0, Duration::from_secs(60));
+        off.record(packet(0));
+        assert!(off.ids().is_empty());
+    }
+
+    #[test]
+    fn digests_only_differ_where_the_histories_do() {
+        let pkts: Vec<_> = (0..64).map(packet).collect();
+        let mut ours = History::new(100, Duration::from_secs(60));
+        let mut theirs = History::new(100, Duration::from_secs(60));
+        for pkt in &pkts {
+            ours.record(pkt.clone());
+        }
+        for pkt in pkts[1..].iter().rev() {
+            theirs.record(pkt.clone());
+        }
+        // the same broadcasts in a different order
+        theirs.record(pkts[0].clone());
+        assert!(ours.differing(&theirs.digest()).is_empty());
+
+        let extra = packet(100);
+        ours.record(extra.clone());
+        let buckets = ours.differing(&theirs.digest());
+        assert_eq!(buckets, vec![bucket(&extra.id) as u8]);
+        let ids = ours.ids_in(&buckets);
+        assert!(ids.contains(&extra.id));
+        assert!(ids.len() < pkts.len() / 2);
+        // a malformed digest just means comparing every bucket
+        assert_eq!(ours.differing(&[]).len(), DIGEST_BUCKETS);
+    }
+
+    #[test]
+    fn digests_are_smaller_than_id_lists() {
+        let mut history = History::new(256, Duration::from_secs(60));
+        for i in 0..256 {
+            history.record(packet(i));
+        }
+        let digest = bincode::serialize(&history.digest()).unwrap().len();
+        let ids = bincode::serialize(&history.ids()).unwrap().len();
+        println!("digest: {} bytes, id list: {} bytes", digest, ids);
+        assert_eq!(digest, 8 + 16 * DIGEST_BUCKETS);
+        assert!(digest * 10 < ids);
+    }
+}
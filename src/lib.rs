@@ -0,0 +1,25 @@
+#![deny(unused_must_use)]
+
+pub mod blob;
+pub mod causal;
+pub mod codec;
+pub mod compress;
+pub mod dedup;
+pub mod dht;
+pub mod filter;
+pub mod gossip;
+pub mod handshake;
+pub mod history;
+pub mod linkstate;
+pub mod node;
+pub mod ordering;
+pub mod peer;
+pub mod proto;
+pub mod pubsub;
+pub mod ratelimit;
+pub mod reliable;
+pub mod routing;
+pub mod rpc;
+pub mod sched;
+#[cfg(test)]
+mod testing;
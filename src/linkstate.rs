@@ -0,0 +1,271 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::routing::Route;
+
+/// A node's description of its own links, flooded to the whole network.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct LinkStateAdvert {
+    pub origin: SocketAddr,
+    /// Bumped every time the origin sends a new advertisement, so stale
+    /// copies still floating around get ignored.
+    pub seq: u64,
+    /// Each neighbor and the cost of the link to it (measured RTT in ms).
+    pub links: Vec<(SocketAddr, u32)>,
+}
+
+struct Path {
+    first_hop: SocketAddr,
+    cost: u32,
+    hops: u16,
+}
+
+struct Entry {
+    lsa: LinkStateAdvert,
+    received: Instant,
+}
+
+/// Everything we know about the topology, and the shortest paths through it.
+pub struct LinkStateDb {
+    me: SocketAddr,
+    entries: HashMap<SocketAddr, Entry>,
+    /// The first hop (as a node address) to each destination, and the total
+    /// cost and hop count of the path. Recomputed whenever the database
+    /// changes.
+    paths: HashMap<SocketAddr, Path>,
+    max_age: Duration,
+}
+
+impl LinkStateDb {
+    /// Advertisements that aren't refreshed within `max_age` are dropped.
+    pub fn new(me: SocketAddr, max_age: Duration) -> Self {
+        Self {
+            me,
+            entries: Default::default(),
+            paths: Default::default(),
+            max_age,
+        }
+    }
+
+    /// Store an advertisement if it is newer than what we have. Returns true
+    /// if it was, meaning it should be flooded on to the other neighbors.
+    pub fn install(&mut self, lsa: LinkStateAdvert) -> bool {
+        if let Some(entry) = self.entries.get(&lsa.origin) {
+            if entry.lsa.seq >= lsa.seq {
+                return false;
+            }
+        }
+        self.entries.insert(
+            lsa.origin,
+            Entry {
+                lsa,
+                received: Instant::now(),
+            },
+        );
+        self.recompute();
+        true
+    }
+
+    pub fn expire(&mut self) {
+        let max_age = self.max_age;
+        let me = self.me;
+        let before = self.entries.len();
+        self.entries
+            .retain(|origin, e| *origin == me || e.received.elapsed() <= max_age);
+        if self.entries.len() != before {
+            self.recompute();
+        }
+    }
+
+    pub fn topology(&self) -> Vec<LinkStateAdvert> {
+        self.entries.values().map(|e| e.lsa.clone()).collect()
+    }
+
+    /// The first hop node on the shortest path to `dest`.
+    pub fn first_hop(&self, dest: &SocketAddr) -> Option<SocketAddr> {
+        self.paths.get(dest).map(|p| p.first_hop)
+    }
+
+    /// Shortest paths as routes, `link_for` maps a neighbor's node address to
+    /// the peer link it's connected on.
+    pub fn routes(&self, link_for: impl Fn(&SocketAddr) -> Option<SocketAddr>) -> Vec<Route> {
+        let now = Instant::now();
+        self.paths
+            .iter()
+            .filter_map(|(dest, path)| {
+                Some(Route {
+                    dest: *dest,
+                    next_hop: link_for(&path.first_hop)?,
+                    hops: path.hops,
+                    cost: Some(path.cost),
+                    updated: now,
+                })
+            })
+            .collect()
+    }
+
+    /// Only count a link if both ends advertise it, so a half dead link (or
+    /// a node that has since gone away) isn't used.
+    fn cost(&self, from: &SocketAddr, to: &SocketAddr) -> Option<u32> {
+        let forward = self
+            .entries
+            .get(from)?
+            .lsa
+            .links
+            .iter()
+            .find(|(n, _)| n == to)?
+            .1;
+        let back = self
+            .entries
+            .get(to)?
+            .lsa
+            .links
+            .iter()
+            .find(|(n, _)| n == from)?
+            .1;
+        Some(forward.max(back).max(1))
+    }
+
+    /// Dijkstra from our own node over the current database.
+    fn recompute(&mut self) {
+        // best cost so far, then the first hop and hop count of that path
+        let mut dist: HashMap<SocketAddr, (u32, Option<SocketAddr>, u16)> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        dist.insert(self.me, (0, None, 0));
+        heap.push(Reverse((0u32, self.me)));
+
+        while let Some(Reverse((d, node))) = heap.pop() {
+            if dist.get(&node).is_some_and(|(best, _, _)| d > *best) {
+                continue;
+            }
+            let (_, first_hop, hops) = dist[&node];
+            let neighbors = match self.entries.get(&node) {
+                Some(e) => e.lsa.links.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+                None => continue,
+            };
+            for next in neighbors {
+                let cost = match self.cost(&node, &next) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                let nd = d.saturating_add(cost);
+                if dist.get(&next).is_none_or(|(best, _, _)| nd < *best) {
+                    let hop = Some(first_hop.unwrap_or(next));
+                    dist.insert(next, (nd, hop, hops.saturating_add(1)));
+                    heap.push(Reverse((nd, next)));
+                }
+            }
+        }
+
+        self.paths = dist
+            .into_iter()
+            .filter_map(|(dest, (cost, first_hop, hops))| {
+                let path = Path {
+                    first_hop: first_hop?,
+                    cost,
+                    hops,
+                };
+                Some((dest, path))
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    fn lsa(origin: u16, seq: u64, links: &[(u16, u32)]) -> LinkStateAdvert {
+        LinkStateAdvert {
+            origin: addr(origin),
+            seq,
+            links: links.iter().map(|(n, cost)| (addr(*n), *cost)).collect(),
+        }
+    }
+
+    /// A database at node 1 holding an advert for every node, with each link
+    /// advertised by both its ends.
+    fn db(links: &[(u16, u16, u32)]) -> LinkStateDb {
+        let mut nodes: HashMap<u16, Vec<(u16, u32)>> = HashMap::new();
+        for (a, b, cost) in links {
+            nodes.entry(*a).or_default().push((*b, *cost));
+            nodes.entry(*b).or_default().push((*a, *cost));
+        }
+        let mut db = LinkStateDb::new(addr(1), Duration::from_secs(60));
+        for (origin, links) in nodes {
+            db.install(lsa(origin, 1, &links));
+        }
+        db
+    }
+
+    fn route(db: &LinkStateDb, dest: u16) -> Option<Route> {
+        db.routes(|node| Some(*node))
+            .into_iter()
+            .find(|r| r.dest == addr(dest))
+    }
+
+    #[test]
+    fn takes_the_fastest_path_not_the_shortest() {
+        // 1 - 2 - 3 is two slow hops, 1 - 4 - 5 - 3 three fast ones
+        let db = db(&[(1, 2, 50), (2, 3, 50), (1, 4, 5), (4, 5, 5), (5, 3, 5)]);
+        assert_eq!(db.first_hop(&addr(3)), Some(addr(4)));
+        let to_three = route(&db, 3).unwrap();
+        assert_eq!(to_three.cost, Some(15));
+        assert_eq!(to_three.hops, 3);
+        assert_eq!(to_three.next_hop, addr(4));
+        assert_eq!(route(&db, 2).unwrap().hops, 1);
+        assert!(route(&db, 1).is_none());
+    }
+
+    #[test]
+    fn links_only_count_when_both_ends_advertise_them() {
+        let mut db = LinkStateDb::new(addr(1), Duration::from_secs(60));
+        db.install(lsa(1, 1, &[(2, 1)]));
+        db.install(lsa(2, 1, &[(1, 1), (3, 1)]));
+        // 3 hasn't said anything about 2 (yet)
+        assert_eq!(db.first_hop(&addr(2)), Some(addr(2)));
+        assert_eq!(db.first_hop(&addr(3)), None);
+        db.install(lsa(3, 1, &[(2, 1)]));
+        assert_eq!(db.first_hop(&addr(3)), Some(addr(2)));
+    }
+
+    #[test]
+    fn a_link_costs_the_worse_of_its_two_ends() {
+        let mut db = LinkStateDb::new(addr(1), Duration::from_secs(60));
+        db.install(lsa(1, 1, &[(2, 3)]));
+        db.install(lsa(2, 1, &[(1, 9)]));
+        assert_eq!(route(&db, 2).unwrap().cost, Some(9));
+    }
+
+    #[test]
+    fn old_adverts_are_ignored() {
+        let mut db = db(&[(1, 2, 1), (2, 3, 1)]);
+        assert!(!db.install(lsa(2, 1, &[(1, 1)])));
+        assert_eq!(db.first_hop(&addr(3)), Some(addr(2)));
+        // a newer one from 2 without the link to 3 cuts it off
+        assert!(db.install(lsa(2, 2, &[(1, 1)])));
+        assert_eq!(db.first_hop(&addr(3)), None);
+    }
+
+    #[tokio::test]
+    async fn expired_adverts_take_their_links_with_them() {
+        tokio::time::pause();
+        let mut db = LinkStateDb::new(addr(1), Duration::ZERO);
+        db.install(lsa(1, 1, &[(2, 1)]));
+        db.install(lsa(2, 1, &[(1, 1)]));
+        assert_eq!(db.first_hop(&addr(2)), Some(addr(2)));
+        tokio::time::advance(Duration::from_millis(5)).await;
+        db.expire();
+        assert_eq!(db.first_hop(&addr(2)), None);
+        // our own advert stays
+        assert_eq!(db.topology().len(), 1);
+    }
+}
@@ -1,11 +1,9 @@
 #![deny(unused_must_use)]
 
-#[allow(dead_code)]
-mod node;
-mod peer;
-mod proto;
 mod support;
 
+use poe_core::{node, peer};
+
 use imgui::*;
 use rand::prelude::*;
 use regex::Regex;
@@ -27,6 +25,8 @@ enum UiCommand {
 /// State shared between the UI and each node.
 struct UiState {
     log: Vec<String>,
+    /// One line for each of the node's neighbors.
+    peers: Vec<String>,
     color: Color,
     tx: broadcast::Sender<UiCommand>,
 }
@@ -35,6 +35,7 @@ impl UiState {
     pub fn new(tx: broadcast::Sender<UiCommand>) -> Self {
         Self {
             log: Default::default(),
+            peers: Default::default(),
             color: Default::default(),
             tx,
         }
@@ -64,22 +65,20 @@ async fn spawn_task(port: u16, peer_strings: &[&str], states: States) {
     // Create the node that we will be listening on. In this PoC
     let mut node = node::Node::<String>::new(port).await.start();
 
-    // Connect to each of the TCP sockets in the peer_strings list
+    // Connect to each of the nodes in the peer_strings list. Both ends of a
+    // pair dial each other here, the nodes sort out which link to keep.
     for s in peer_strings {
         // Parse the address provided
         let addr: std::net::SocketAddr = s.parse().unwrap();
-        // Keep trying to connect to the tcp socket using tokio. Loop until
-        // it succeeds (TODO: make this just fail lol)
-        let con = loop {
+        // Keep asking the node to connect until it succeeds, the other node
+        // might not be listening yet (TODO: make this just fail lol)
+        loop {
             println!("trying {} from {}", addr, port);
-            if let Ok(stream) = tokio::net::TcpStream::connect(&addr).await {
-                break stream;
+            if node.connect(addr).await.is_ok() {
+                break;
             }
             println!("Failed!");
-        };
-
-        // Tell the node to connect to the peer we just joined to.
-        node.send_cmd(node::MetaCommand::AddPeer(con, addr)).await;
+        }
     }
 
     /*
@@ -91,9 +90,17 @@ async fn spawn_task(port: u16, peer_strings: &[&str], states: States) {
      * Once one of these futures completes, either data_opt or cmd will be set, and the code inside
      * the block is run with the value sent over the channel.
      */
+    let re = Regex::new(r"c\((\d*\.?\d*),(\d*\.?\d*),(\d*\.?\d*),(\d*\.?\d*)\)").unwrap();
+    let mut refresh = tokio::time::interval(std::time::Duration::from_secs(1));
     loop {
         tokio::select! {
 
+            /* Every so often, grab what the node knows about its neighbors for the UI to show. */
+            _ = refresh.tick() => {
+                let peers = node.peers().await.iter().map(describe_peer).collect();
+                state.lock().unwrap().peers = peers;
+            }
+
             /*
              * Wait for data over the network. In this example, the data is always going to be a
              * string, as we construct the nodes as node::Node::<String>::new(). This means
@@ -105,8 +112,6 @@ async fn spawn_task(port: u16, peer_strings: &[&str], states: States) {
             data_opt = node.recv() => {
                 if let Some(data) = data_opt {
                     let mut state = state.lock().unwrap();
-                    /* Parse the regexj */
-                    let re = Regex::new(r"c\((\d*\.?\d*),(\d*\.?\d*),(\d*\.?\d*),(\d*\.?\d*)\)").unwrap();
                     /* Grab the capture */
                     let captures = re.captures(&data.0);
                     // If the capture exists (regex matched), grab the colors from it, parsing
@@ -142,14 +147,17 @@ async fn spawn_task(port: u16, peer_strings: &[&str], states: States) {
              */
             cmd = rx.recv() => {
                 if let Ok(cmd) = cmd {
-                    let mut state = state.lock().unwrap();
                     match cmd {
                         /* Given the color, format it to a string, and broadcast it. */
                         UiCommand::ChangeColor(newcolor) => {
-                            state.log.push(format!("Changing color to: '{:?}'", newcolor));
+                            /* don't hold the state lock across the await, the UI thread wants it too */
+                            {
+                                let mut state = state.lock().unwrap();
+                                state.log.push(format!("Changing color to: '{:?}'", newcolor));
+                                state.color = newcolor;
+                            }
                             node.broadcast(
                                 format!("c({},{},{},{})", newcolor[0], newcolor[1], newcolor[2], newcolor[3])).await;
-                            state.color = newcolor;
                         }
                     }
                 }
@@ -158,6 +166,23 @@ async fn spawn_task(port: u16, peer_strings: &[&str], states: States) {
     }
 }
 
+/// A neighbor and the traffic to and from it, on one line.
+fn describe_peer(peer: &peer::PeerInfo) -> String {
+    format!(
+        "{} via {} ({:?}, up {}s): out {} msgs/{}B, in {} msgs/{}B, rtt {}",
+        peer.node.unwrap_or(peer.addr),
+        peer.addr,
+        peer.direction,
+        peer.connected_since.elapsed().as_secs(),
+        peer.messages_sent,
+        peer.bytes_sent,
+        peer.messages_received,
+        peer.bytes_received,
+        peer.rtt
+            .map_or("?".to_owned(), |rtt| format!("{}ms", rtt.as_millis())),
+    )
+}
+
 #[tokio::main]
 async fn comm_thread(states: States) {
     /*
@@ -227,14 +252,17 @@ fn main() {
                     [rand.gen::<f32>() * 1024f32, rand.gen::<f32>() * 768f32],
                     Condition::FirstUseEver,
                 )
-                .size([300.0, 240.0], Condition::FirstUseEver)
-                .build(&ui, || {
+                .size([420.0, 280.0], Condition::FirstUseEver)
+                .build(ui, || {
                     ui.text(format!("Port: {}", port));
+                    for peer in state.lock().unwrap().peers.iter() {
+                        ui.text(peer);
+                    }
                     ui.separator();
 
                     let mut color = state.lock().unwrap().color;
                     let ce = ColorEdit::new(im_str!("color_edit"), &mut color);
-                    if ce.build(&ui) {
+                    if ce.build(ui) {
                         state
                             .lock()
                             .unwrap()
@@ -250,7 +278,7 @@ fn main() {
                         .border(true)
                         .build(ui, || {
                             for event in state.lock().unwrap().log.iter().rev() {
-                                ui.text(event.to_string());
+                                ui.text(event);
                             }
                         });
                 });
@@ -1,170 +1,1470 @@
 use std::{
     collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    io,
     marker::PhantomData,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
 };
 
 use crate::{
-    peer::Peer,
-    proto::{Operation, Packet, Payload, SanePayload},
+    blob::{BlobError, BlobId, BlobMsg, BlobStore},
+    causal::{CausalBuffer, VectorClock},
+    codec::CodecKind,
+    compress::Compression,
+    dedup::{now_millis, SeenSet},
+    dht::{Contact, Dht, DhtError, DhtMsg, Done, NodeKey},
+    filter::{BloomFilter, FilterParams},
+    gossip::{BroadcastStrategy, Plumtree},
+    handshake::{handshake, Feature, HandshakeError, Hello},
+    history::History,
+    linkstate::{LinkStateAdvert, LinkStateDb},
+    ordering::{ReorderBuffer, Seq},
+    peer::{Direction, Framing, Peer, PeerInfo},
+    proto::{ControlMsg, GossipMsg, Operation, Packet, Payload, Priority, SanePayload},
+    pubsub::{Interest, Subscriptions},
+    ratelimit::{InboundLimits, OverLimit, RateLimit},
+    reliable::{DeliveryError, DeliveryReport, ReliableSender, Retry},
+    routing::{Route, RoutingMode, RoutingTable},
+    rpc::{PendingCalls, RequestHandler, RpcError},
+    sched::{lanes, PriorityLanes},
 };
 
+use futures::{future::join_all, stream::FuturesUnordered, FutureExt, StreamExt};
 use lru::LruCache;
+use rand::seq::IteratorRandom;
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
 };
 
+use std::time::Duration;
 use uuid::Uuid;
 
 const MSG_CHAN_CAPACITY: usize = 128;
-const SEEN_CACHE_CAPACITY: usize = 128;
+/// Packets each link can have waiting for the node.
+const INBOUND_QUEUE_LEN: usize = 32;
+const DELIVERED_CACHE_CAPACITY: usize = 1024;
 const META_CHAN_CAPACITY: usize = 16;
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// The most a broadcast's path filter may be wrong about a neighbor having
+/// seen it before we stop skipping neighbors because of it.
+const MAX_PATH_FILTER_FP: f64 = 0.01;
+/// How long a neighbor we hang up on gets to take what we still had queued
+/// for it.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+type ConnectReply = oneshot::Sender<Result<SocketAddr, HandshakeError>>;
+
+/// A new connection that has said hello, ready to become a peer.
+struct Handshaken {
+    stream: TcpStream,
+    addr: SocketAddr,
+    hello: Hello,
+    direction: Direction,
+    /// Whoever asked for the connection, if anyone.
+    reply: Option<ConnectReply>,
+}
+
+/// Tunables for a node. Everything here has a sane default, so most callers
+/// can just use `Node::new`.
+#[derive(Clone, Debug)]
+pub struct NodeConfig {
+    /// Size of the filter that broadcasts carry to record where they have
+    /// been. Must match across the network. Neighbors the filter says have
+    /// seen a broadcast are skipped, so a false positive can keep it from a
+    /// neighbor that only we could have reached. The filter is only trusted
+    /// while its false positive rate is under `MAX_PATH_FILTER_FP`, past that
+    /// (a path longer than it was sized for) everyone gets the broadcast and
+    /// their own duplicate suppression sorts it out.
+    pub path_filter: FilterParams,
+    pub broadcast: BroadcastStrategy,
+    /// How long a Plumtree node waits for a message it heard about through an
+    /// IHAVE before asking for it with a GRAFT.
+    pub graft_timeout: Duration,
+    pub routing: RoutingMode,
+    /// How often we advertise our routes (or links) to each neighbor and
+    /// measure link RTTs. Routes that aren't refreshed for three of these are
+    /// considered dead.
+    pub route_interval: Duration,
+    /// Run a Kademlia DHT alongside the mesh, see `RunningNode::dht_put`.
+    pub dht: bool,
+    /// How long a DHT query gets before the contact is considered dead.
+    pub dht_timeout: Duration,
+    /// How often a node with subscriptions re-announces them. Interest that
+    /// isn't refreshed for three of these is dropped.
+    pub interest_interval: Duration,
+    /// How long a reliable broadcast waits for acks before resending to the
+    /// nodes that haven't confirmed it.
+    pub reliable_retry: Duration,
+    /// Deliver each origin's broadcasts in the order it sent them. Messages
+    /// that arrive early are held back until the ones before them show up.
+    pub ordered: bool,
+    /// How long an ordered node holds messages back waiting for a missing
+    /// one before giving up on it.
+    pub reorder_wait: Duration,
+    /// Deliver broadcasts in causal order: a message is held back until
+    /// everything its sender had delivered before sending it has been
+    /// delivered here too. Every node in the network should agree on this.
+    /// Gaps are waited on for `reorder_wait`, same as ordered delivery.
+    pub causal: bool,
+    /// How many recent broadcasts to keep for replaying to neighbors that
+    /// reconnect after missing them. 0 turns replay off.
+    pub history_len: usize,
+    /// Broadcasts older than this aren't replayed.
+    pub history_age: Duration,
+    /// How often we compare broadcast histories with a random neighbor and
+    /// swap whatever either side is missing.
+    pub anti_entropy_interval: Duration,
+    /// How long packet ids are remembered for catching duplicates. Packets
+    /// sent longer ago than this are dropped, so it has to cover the worst
+    /// case delivery time, including `history_age` for replayed broadcasts
+    /// (and any clock skew between nodes).
+    pub dedup_window: Duration,
+    /// How long a new connection gets to say hello before it's dropped.
+    pub handshake_timeout: Duration,
+    /// Codecs we're willing to use on the wire, most preferred first. Each
+    /// link uses the first one both ends support (going by the preferences
+    /// of the end with the lower address).
+    pub codecs: Vec<CodecKind>,
+    /// Frame compressions we're willing to use, most preferred first. Links
+    /// where the two ends have none in common go uncompressed.
+    pub compression: Vec<Compression>,
+    /// Frames smaller than this many bytes are sent uncompressed.
+    pub compress_threshold: usize,
+    /// Packets that encode to more than this many bytes are split up, so a big
+    /// message doesn't hold up everything else on the link while it's sent.
+    pub fragment_size: usize,
+    /// How long to wait for the rest of a split up packet before giving up on
+    /// it.
+    pub reassembly_timeout: Duration,
+    /// How long a neighbor gets to answer a blob chunk request before it's
+    /// asked of another neighbor that has the blob.
+    pub blob_timeout: Duration,
+    /// Links we haven't sent anything on for this long get a heartbeat.
+    /// Neighbors we haven't heard from in three of these are dropped.
+    pub heartbeat_interval: Duration,
+    /// How many frames a second each neighbor may send us, counting each
+    /// fragment of a big packet. None means no limit.
+    pub peer_rate_limit: Option<RateLimit>,
+    /// The same, for all neighbors together. Packets over this are always
+    /// dropped, whatever `over_rate_limit` says.
+    pub global_rate_limit: Option<RateLimit>,
+    /// What happens to a neighbor that goes over `peer_rate_limit`.
+    pub over_rate_limit: OverLimit,
+    /// How many critical packets a second each neighbor may send us. The
+    /// rest are handled as normal ones, so a neighbor can't jump the queue
+    /// with everything it sends.
+    pub critical_rate_limit: RateLimit,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            path_filter: Default::default(),
+            broadcast: Default::default(),
+            graft_timeout: Duration::from_millis(500),
+            routing: Default::default(),
+            route_interval: Duration::from_secs(1),
+            dht: false,
+            dht_timeout: Duration::from_secs(2),
+            interest_interval: Duration::from_secs(10),
+            reliable_retry: Duration::from_millis(500),
+            ordered: false,
+            reorder_wait: Duration::from_secs(1),
+            causal: false,
+            history_len: 256,
+            history_age: Duration::from_secs(300),
+            anti_entropy_interval: Duration::from_secs(5),
+            dedup_window: Duration::from_secs(600),
+            handshake_timeout: Duration::from_secs(5),
+            codecs: CodecKind::ALL.to_vec(),
+            compression: vec![Compression::Lz4, Compression::Zstd],
+            compress_threshold: 256,
+            fragment_size: 16 * 1024,
+            reassembly_timeout: Duration::from_secs(30),
+            blob_timeout: Duration::from_secs(5),
+            heartbeat_interval: Duration::from_secs(1),
+            peer_rate_limit: None,
+            global_rate_limit: None,
+            over_rate_limit: OverLimit::Drop,
+            critical_rate_limit: RateLimit {
+                rate: 10,
+                burst: 100,
+            },
+        }
+    }
+}
 
 pub struct Node<M> {
-    listener: TcpListener,
+    /// Taken by `run`, so it can be closed before the rest of the node.
+    listener: Option<TcpListener>,
     port: u16,
+    addr: SocketAddr,
+    config: NodeConfig,
     peers: HashMap<SocketAddr, Peer<M>>,
     // pub(super) known_peers: HashSet<SocketAddr>,
-    inbound_packets: mpsc::Receiver<Packet<M>>,
-    tx: mpsc::Sender<Packet<M>>,
-    seen_msgs: LruCache<Uuid, ()>,
+    /// Packets read off every link, most urgent first and otherwise taken in
+    /// turn from each.
+    inbound_packets: PriorityLanes<Packet<M>>,
+    /// Connections that have finished their handshake, ready to be peers.
+    handshakes: mpsc::Receiver<Handshaken>,
+    handshake_tx: mpsc::Sender<Handshaken>,
+    /// Links the reader hung up on for going over the rate limit.
+    cut_off: mpsc::Receiver<SocketAddr>,
+    cut_off_tx: mpsc::Sender<SocketAddr>,
+    /// Handshakes and hang-ups going on in the background, so shutting down
+    /// can wait for them.
+    tasks: FuturesUnordered<JoinHandle<()>>,
+    /// Nodes we won't have as peers.
+    banned: HashSet<SocketAddr>,
+    /// Hosts we won't have links with, whatever node they say they are.
+    banned_ips: HashSet<IpAddr>,
+    seen_msgs: SeenSet,
+    /// Shared with the reader task of every link.
+    limits: Arc<InboundLimits>,
+    plumtree: Plumtree<M>,
+    routes: RoutingTable,
+    linkstate: LinkStateDb,
+    /// When this node started, in ms since the unix epoch. Sequence numbers
+    /// that other nodes remember across our restarts start from here, so
+    /// they keep going up when we come back.
+    epoch: u64,
+    /// Since when we may have missed broadcasts, in ms since the unix epoch:
+    /// boot, or when we last lost all our neighbors.
+    offline_since: u64,
+    lsa_seq: u64,
+    /// Our links changed, send a fresh link state advertisement.
+    lsa_dirty: bool,
+    /// Outstanding pings, by peer: the nonce and when it was sent.
+    pings: HashMap<SocketAddr, (u64, Instant)>,
+    last_advert: Instant,
+    dht: Dht,
+    subs: Subscriptions,
+    last_interest: Instant,
+    calls: PendingCalls<M>,
+    handler: Option<RequestHandler<M>>,
+    reliable: ReliableSender<M>,
+    /// Ids of reliable messages already handed to the application, since
+    /// retransmissions come in as new packets.
+    delivered: LruCache<Uuid, ()>,
+    /// Sequence number of the last broadcast we originated.
+    broadcast_seq: u64,
+    reorder: ReorderBuffer<M>,
+    causal: CausalBuffer<M>,
+    history: History<M>,
+    blobs: BlobStore,
+    last_digest: Instant,
+    stats: NodeStats,
     phantom: PhantomData<M>,
 }
 
 impl<M: SanePayload> Node<M> {
     pub async fn new(port: u16) -> Self {
-        println!("Listening at 127.0.0.1:{}", port);
+        Self::with_config(port, NodeConfig::default()).await
+    }
+
+    /// Port 0 picks a free one, see `RunningNode::addr` for which.
+    pub async fn with_config(port: u16, config: NodeConfig) -> Self {
         let listener = TcpListener::bind((Ipv4Addr::new(127, 0, 0, 1), port))
             .await
             .unwrap();
-        let (tx, rx) = mpsc::channel(MSG_CHAN_CAPACITY);
+        let port = listener.local_addr().unwrap().port();
+        println!("Listening at 127.0.0.1:{}", port);
+        let (handshake_tx, handshakes) = mpsc::channel(META_CHAN_CAPACITY);
+        let (cut_off_tx, cut_off) = mpsc::channel(META_CHAN_CAPACITY);
+        // TODO: get a real address
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let epoch = now_millis();
 
         Self {
-            listener,
+            listener: Some(listener),
             // acceptor,
             port,
+            addr,
+            plumtree: Plumtree::new(config.graft_timeout),
+            routes: RoutingTable::new(addr, config.route_interval * 3),
+            linkstate: LinkStateDb::new(addr, config.route_interval * 3),
+            epoch,
+            offline_since: epoch,
+            lsa_seq: epoch,
+            lsa_dirty: false,
+            pings: Default::default(),
+            last_advert: Instant::now(),
+            dht: Dht::new(addr, config.dht_timeout),
+            subs: Subscriptions::new(addr, config.interest_interval * 3, epoch),
+            last_interest: Instant::now(),
+            calls: Default::default(),
+            handler: None,
+            reliable: ReliableSender::new(config.reliable_retry),
+            delivered: LruCache::new(DELIVERED_CACHE_CAPACITY),
+            broadcast_seq: 0,
+            reorder: ReorderBuffer::new(config.reorder_wait),
+            causal: CausalBuffer::new(addr, config.reorder_wait),
+            history: History::new(config.history_len, config.history_age),
+            blobs: BlobStore::new(config.blob_timeout),
+            last_digest: Instant::now(),
+            seen_msgs: SeenSet::new(config.dedup_window),
+            limits: Arc::new(InboundLimits::new(
+                config.peer_rate_limit,
+                config.global_rate_limit,
+                config.over_rate_limit,
+                config.critical_rate_limit,
+            )),
+            config,
             peers: Default::default(),
             // known_peers: Default::default(),
-            inbound_packets: rx,
-            seen_msgs: LruCache::new(SEEN_CACHE_CAPACITY),
-            tx,
+            inbound_packets: Default::default(),
+            handshakes,
+            handshake_tx,
+            cut_off,
+            cut_off_tx,
+            tasks: Default::default(),
+            banned: Default::default(),
+            banned_ips: Default::default(),
+            stats: Default::default(),
             phantom: PhantomData,
         }
     }
 
-    fn add_peer(&mut self, stream: TcpStream, addr: SocketAddr) {
-        let peer = Peer::new(stream, self.tx.clone());
+    /// What we tell new peers about ourselves.
+    fn hello(&self) -> Hello {
+        let mut features = vec![
+            Feature::Broadcast(self.config.broadcast),
+            Feature::Routing(self.config.routing),
+        ];
+        if self.config.dht {
+            features.push(Feature::Dht);
+        }
+        if self.config.causal {
+            features.push(Feature::Causal);
+        }
+        features.extend(self.config.codecs.iter().map(|c| Feature::Codec(*c)));
+        features.extend(
+            self.config
+                .compression
+                .iter()
+                .map(|c| Feature::Compression(*c)),
+        );
+        Hello {
+            node: self.addr,
+            epoch: self.epoch,
+            features,
+        }
+    }
+
+    /// Say hello on a new connection, once `connect` has made it. This
+    /// happens off on its own task, so a slow (or silent) peer doesn't hold
+    /// the node up, and the connection only becomes a peer once both sides
+    /// are happy with each other. `reply` hears how that went.
+    fn add_peer(
+        &mut self,
+        connect: impl Future<Output = io::Result<TcpStream>> + Send + 'static,
+        addr: SocketAddr,
+        direction: Direction,
+        reply: Option<ConnectReply>,
+    ) {
+        let hello = self.hello();
+        let timeout = self.config.handshake_timeout;
+        let ready = self.handshake_tx.clone();
+        let port = self.port;
+        self.tasks.push(tokio::spawn(async move {
+            let greet = async {
+                let mut stream = connect.await?;
+                let theirs = handshake(&mut stream, &hello).await?;
+                Ok((stream, theirs))
+            };
+            match tokio::time::timeout(timeout, greet)
+                .await
+                .unwrap_or(Err(HandshakeError::Timeout))
+            {
+                Ok((stream, hello)) => {
+                    let done = Handshaken {
+                        stream,
+                        addr,
+                        hello,
+                        direction,
+                        reply,
+                    };
+                    let _ = ready.send(done).await;
+                }
+                Err(e) => {
+                    println!("[{}] refusing {}: {}", port, addr, e);
+                    if let Some(reply) = reply {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+        }));
+    }
+
+    async fn peer_ready(&mut self, done: Handshaken) {
+        let Handshaken {
+            stream,
+            addr,
+            hello,
+            direction,
+            reply,
+        } = done;
+        if self.is_banned(&addr, &hello.node) {
+            println!(
+                "[{}] refusing {}: {}",
+                self.port,
+                addr,
+                HandshakeError::Banned
+            );
+            if let Some(reply) = reply {
+                let _ = reply.send(Err(HandshakeError::Banned));
+            }
+            return;
+        }
+        let dialer = match direction {
+            Direction::Outbound => match stream.local_addr() {
+                Ok(local) => (self.addr, local),
+                Err(e) => {
+                    if let Some(reply) = reply {
+                        let _ = reply.send(Err(HandshakeError::Io(e)));
+                    }
+                    return;
+                }
+            },
+            Direction::Inbound => (hello.node, addr),
+        };
+        if let Some(reply) = reply {
+            let _ = reply.send(Ok(hello.node));
+        }
+        let already = self
+            .peers
+            .iter()
+            .find(|(_, p)| p.node == Some(hello.node))
+            .map(|(link, p)| (*link, p.dialer, p.epoch));
+        if let Some((link, theirs, epoch)) = already {
+            // we've each dialed the other, someone dialed twice, or the node
+            // restarted before we noticed its old link was gone. Both ends
+            // come to the same answer: the link to the newer run of the node,
+            // or if it's the same run the one with the lowest dialer
+            println!(
+                "[{}] {} and {} both go to {}",
+                self.port, link, addr, hello.node
+            );
+            let stale = epoch < hello.epoch || (epoch == hello.epoch && Some(dialer) < theirs);
+            if !stale {
+                // the other end drops it too, nothing on it is wanted
+                return;
+            }
+            self.disconnect(&link);
+        }
+        let dht = hello.has(&Feature::Dht);
+        let ours = self.hello();
+        let framing = Framing {
+            codec: ours.codec(&hello).expect("checked in the handshake"),
+            compression: ours.compression(&hello),
+            compress_threshold: self.config.compress_threshold,
+            fragment_size: self.config.fragment_size,
+            reassembly_timeout: self.config.reassembly_timeout,
+        };
+        let (tx, rx) = lanes(INBOUND_QUEUE_LEN);
+        let mut peer = Peer::new(
+            stream,
+            addr,
+            direction,
+            framing,
+            self.limits.clone(),
+            tx,
+            self.cut_off_tx.clone(),
+        );
+        self.inbound_packets.add(addr, rx);
+        peer.node = Some(hello.node);
+        peer.epoch = hello.epoch;
+        peer.features = hello.features;
+        peer.dialer = Some(dialer);
         self.peers.insert(addr, peer);
+        self.plumtree.add_peer(addr);
+
+        if self.config.dht && dht {
+            // neighbors are our way into the DHT, look ourselves up through
+            // the first one to fill in the buckets
+            let bootstrap = self.dht.is_empty();
+            self.dht.observe(Contact::new(hello.node));
+            if bootstrap {
+                let me = NodeKey::for_node(&self.addr);
+                let out = self.dht.lookup(me, Done::Bootstrap);
+                self.send_dht(out).await;
+            }
+        }
+
+        // let the new neighbor know which topics are wanted on our side
+        let mut interests = self.subs.known();
+        if self.subs.has_local() {
+            interests.push(self.subs.current());
+        }
+        for interest in interests {
+            self.send_link(addr, ControlMsg::Interest(interest)).await;
+        }
+
+        let out = self.blobs.add_peer(addr);
+        self.send_blob(out).await;
+
+        // and which broadcasts we already have, in case we've been away. If
+        // we still have other neighbors we can only have missed what went out
+        // while this link was failing
+        let since = if self.peers.len() > 1 {
+            now_millis().saturating_sub(self.link_failure_ms())
+        } else {
+            self.offline_since
+        };
+        let have = self.history.summary();
+        self.send_link(addr, ControlMsg::Summary { since, have })
+            .await;
+    }
+
+    /// Forget about a peer whose link has failed.
+    fn remove_peer(&mut self, addr: &SocketAddr) {
+        if self.peers.remove(addr).is_some() {
+            println!("[{}] lost peer {}", self.port, addr);
+            if self.peers.is_empty() {
+                self.offline_since = now_millis().saturating_sub(self.link_failure_ms());
+            }
+        }
+        self.inbound_packets.remove(addr);
+        self.plumtree.remove_peer(addr);
+        self.routes.link_down(addr);
+        self.subs.link_down(addr);
+        self.blobs.link_down(addr);
+        self.pings.remove(addr);
+        self.lsa_dirty = true;
     }
 
     async fn run(
         mut self,
         mut metarx: mpsc::Receiver<MetaCommand<M>>,
+        mut alarms: mpsc::Receiver<M>,
         datatx: mpsc::Sender<(M, SocketAddr)>,
     ) {
+        let listener = self.listener.take().expect("nodes only run once");
+        let mut tick = tokio::time::interval(TICK_INTERVAL);
         loop {
             tokio::select! {
-                new_peer = self.listener.accept() => {
+                // alarms jump the queue, whatever else is waiting
+                biased;
+                Some(msg) = alarms.recv() => {
+                    self.originate(msg, Priority::Critical).await;
+                }
+                new_peer = listener.accept() => {
                     match new_peer {
                         Ok((stream, addr)) => {
                             // println!("accept from {}!", addr);
-                            self.add_peer(stream /* .into() */, addr);
+                            self.add_peer(async { Ok(stream) }, addr, Direction::Inbound, None);
                         },
                         Err(e) => panic!("TcpListener::accept failed: {}", e),
                     }
                 }
                 meta = metarx.recv() => {
-                    match meta.expect("meta channel closed") {
+                    let meta = match meta {
+                        Some(meta) => meta,
+                        // whoever ran us is gone, nobody left to serve
+                        None => break,
+                    };
+                    match meta {
                         MetaCommand::Die => {
                             println!("Node Terminating");
                             break;
                         },
-                        MetaCommand::Broadcast(msg) => {
-                            println!("Told to broadcast '{:?}'", msg);
-                            let payload = Payload::Message(msg);
-                            let mut seen = HashSet::new();
-                            seen.insert(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), self.port));
-
-                            let op = Operation::Broadcast {
-                                seen,
-                                hops: 0, // ???
-                            };
-
-
-                            let my_addr = SocketAddr::from(([127, 0, 0, 1], self.port));
-                            let packet = Packet::new(op, my_addr, payload);
-                            self.broadcast(packet).await;
+                        MetaCommand::Shutdown(timeout) => {
+                            drop(listener);
+                            self.leave(&mut alarms, timeout).await;
+                            break;
+                        }
+                        MetaCommand::Broadcast(msg, priority) => {
+                            self.originate(msg, priority).await;
                         },
                         MetaCommand::AddPeer(stream, addr) => {
-                            self.add_peer(stream, addr);
+                            self.add_peer(async { Ok(stream) }, addr, Direction::Outbound, None);
+                        }
+                        MetaCommand::Connect(addr, reply) => {
+                            if self.is_banned(&addr, &addr) {
+                                let _ = reply.send(Err(HandshakeError::Banned));
+                                continue;
+                            }
+                            self.add_peer(TcpStream::connect(addr), addr, Direction::Outbound, Some(reply));
+                        }
+                        MetaCommand::Disconnect(peer, reply) => {
+                            let links = self.links_to(&peer);
+                            for link in &links {
+                                self.disconnect(link);
+                            }
+                            let _ = reply.send(!links.is_empty());
+                        }
+                        MetaCommand::Ban(node) => {
+                            println!("[{}] banning {}", self.port, node);
+                            self.banned.insert(node);
+                            for link in self.links_to(&node) {
+                                self.disconnect(&link);
+                            }
+                        }
+                        MetaCommand::Unban(node) => {
+                            self.banned.remove(&node);
+                        }
+                        MetaCommand::BanIp(ip) => {
+                            println!("[{}] banning {}", self.port, ip);
+                            self.banned_ips.insert(ip);
+                            let links: Vec<_> = self.peers.keys().filter(|l| l.ip() == ip).copied().collect();
+                            for link in links {
+                                self.disconnect(&link);
+                            }
+                        }
+                        MetaCommand::UnbanIp(ip) => {
+                            self.banned_ips.remove(&ip);
+                        }
+                        MetaCommand::Peers(reply) => {
+                            let _ = reply.send(self.peers.values().map(Peer::info).collect());
+                        }
+                        MetaCommand::Publish(topic, msg) => {
+                            let op = Operation::Topic { topic };
+                            let packet = Packet::new(op, self.addr, Payload::Message(msg));
+                            self.seen_msgs.insert(packet.id);
+                            self.publish(packet, None).await;
+                        }
+                        MetaCommand::Subscribe(topic) => {
+                            if let Some(interest) = self.subs.subscribe(topic) {
+                                self.announce_interest(interest).await;
+                            }
+                        }
+                        MetaCommand::Unsubscribe(topic) => {
+                            if let Some(interest) = self.subs.unsubscribe(&topic) {
+                                self.announce_interest(interest).await;
+                            }
+                        }
+                        MetaCommand::BroadcastReliable(msg, targets, timeout, reply) => {
+                            // nobody named means everyone we know of
+                            let targets = if targets.is_empty() {
+                                self.route_list().into_iter().map(|r| r.dest).collect()
+                            } else {
+                                targets.into_iter().collect()
+                            };
+                            let id = Uuid::new_v4();
+                            let seq = Some(self.next_broadcast_seq());
+                            self.reliable.start(id, msg.clone(), seq, targets, Instant::now() + timeout, reply);
+
+                            let mut seen = BloomFilter::new(self.config.path_filter);
+                            seen.insert(&self.addr);
+                            let op = Operation::Broadcast { seen, hops: 0 };
+                            let mut packet = Packet::new(op, self.addr, Payload::Reliable { id, msg });
+                            packet.seq = seq;
+                            self.seen_msgs.insert(packet.id);
+                            self.relay(packet, None).await;
+                        }
+                        MetaCommand::Request(target, msg, timeout, reply) => {
+                            if self.next_hop(&target).is_none() {
+                                let _ = reply.send(Err(RpcError::NoRoute));
+                                continue;
+                            }
+                            let op = Operation::Directed { target };
+                            let packet = Packet::new(op, self.addr, Payload::Request(msg));
+                            self.calls.insert(packet.id, target, Instant::now() + timeout, reply);
+                            self.seen_msgs.insert(packet.id);
+                            self.forward(packet).await;
+                        }
+                        MetaCommand::SetHandler(handler) => {
+                            self.handler = Some(handler);
+                        }
+                        MetaCommand::SendTo(target, msg) => {
+                            self.send_directed(target, Payload::Message(msg)).await;
+                        }
+                        MetaCommand::DhtPut(_, _, reply) if !self.config.dht => {
+                            let _ = reply.send(Err(DhtError::Disabled));
+                        }
+                        MetaCommand::DhtPut(key, value, reply) => {
+                            let out = self.dht.lookup(NodeKey::hash(&key), Done::Store(value, reply));
+                            self.send_dht(out).await;
+                        }
+                        MetaCommand::DhtGet(_, reply) if !self.config.dht => {
+                            let _ = reply.send(Err(DhtError::Disabled));
+                        }
+                        MetaCommand::DhtGet(key, reply) => {
+                            let key = NodeKey::hash(&key);
+                            match self.dht.get_local(&key) {
+                                Some(value) => {
+                                    let _ = reply.send(Ok(Some(value)));
+                                }
+                                None => {
+                                    let out = self.dht.lookup(key, Done::Value(reply));
+                                    self.send_dht(out).await;
+                                }
+                            }
+                        }
+                        MetaCommand::DhtFindNode(_, reply) if !self.config.dht => {
+                            let _ = reply.send(Err(DhtError::Disabled));
+                        }
+                        MetaCommand::DhtFindNode(target, reply) => {
+                            let out = self.dht.lookup(target, Done::Nodes(reply));
+                            self.send_dht(out).await;
+                        }
+                        MetaCommand::BlobPut(data, reply) => {
+                            let (id, out) = self.blobs.put(data);
+                            let _ = reply.send(id);
+                            self.send_blob(out).await;
+                        }
+                        MetaCommand::BlobGet(id, timeout, reply) => {
+                            let out = self.blobs.fetch(id, Instant::now() + timeout, reply);
+                            self.send_blob(out).await;
+                        }
+                        MetaCommand::Stats(reply) => {
+                            self.stats.messages_late = self.reorder.late + self.causal.late;
+                            self.stats.rate_limited = self.limits.dropped.load(Ordering::Relaxed);
+                            self.stats.rate_limit_disconnects =
+                                self.limits.disconnects.load(Ordering::Relaxed);
+                            let _ = reply.send(self.stats.clone());
+                        }
+                        MetaCommand::Routes(reply) => {
+                            let _ = reply.send(self.route_list());
+                        }
+                        MetaCommand::Topology(reply) => {
+                            let _ = reply.send(self.linkstate.topology());
                         }
                     }
                 }
-                pkt = self.inbound_packets.recv() => {
-                    let pkt = pkt.expect("no senders???");
-                    self.handle_packet(pkt, &datatx).await;
+                Some(link) = self.cut_off.recv() => {
+                    self.disconnect(&link);
+                }
+                ready = self.handshakes.recv() => {
+                    self.peer_ready(ready.expect("we hold a sender")).await;
                 }
+                (from, pkt) = self.inbound_packets.recv() => {
+                    self.stats.packets_received += 1;
+                    self.handle_packet(from, pkt, &datatx).await;
+                }
+                _ = tick.tick() => {
+                    self.on_tick(&datatx).await;
+                }
+            }
+        }
+    }
+
+    /// Say goodbye to every neighbor once it has everything we queued for
+    /// it, and hang up. Handshakes and hang-ups still going on in the
+    /// background get until `timeout` too, then they're cut short.
+    async fn leave(&mut self, alarms: &mut mpsc::Receiver<M>, timeout: Duration) {
+        println!("[{}] shutting down", self.port);
+        let deadline = tokio::time::Instant::now() + timeout;
+        // alarms raised before the shutdown still go out
+        while let Some(Some(msg)) = alarms.recv().now_or_never() {
+            self.originate(msg, Priority::Critical).await;
+        }
+        let goodbye = self.goodbye();
+        let peers: Vec<_> = self.peers.drain().map(|(_, peer)| peer).collect();
+        join_all(peers.into_iter().map(|peer| peer.close(&goodbye, timeout))).await;
+        let pending = async { while self.tasks.next().await.is_some() {} };
+        if tokio::time::timeout_at(deadline, pending).await.is_err() {
+            for task in self.tasks.iter() {
+                task.abort();
             }
+            while self.tasks.next().await.is_some() {}
         }
     }
 
-    async fn handle_packet(&mut self, pkt: Packet<M>, datatx: &mpsc::Sender<(M, SocketAddr)>) {
-        if self.seen_msgs.contains(&pkt.id) {
+    fn goodbye(&self) -> Packet<M> {
+        let mut goodbye = Packet::new(
+            Operation::Link,
+            self.addr,
+            Payload::Control(ControlMsg::Goodbye),
+        );
+        // it's the last thing on the wire, this keeps it behind everything
+        // else in the other end's queues too
+        goodbye.priority = Priority::Bulk;
+        goodbye
+    }
+
+    /// Whether a link at `link` to the node `node` is one we refuse.
+    fn is_banned(&self, link: &SocketAddr, node: &SocketAddr) -> bool {
+        self.banned.contains(node) || self.banned_ips.contains(&link.ip())
+    }
+
+    /// Our links to `peer`, which can be a link address or a node address.
+    fn links_to(&self, peer: &SocketAddr) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(addr, p)| *addr == peer || p.node.as_ref() == Some(peer))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Hang up on a neighbor nicely, in the background.
+    fn disconnect(&mut self, link: &SocketAddr) {
+        if let Some(peer) = self.peers.remove(link) {
+            println!("[{}] disconnecting from {}", self.port, link);
+            let goodbye = self.goodbye();
+            self.tasks.push(tokio::spawn(async move {
+                peer.close(&goodbye, DISCONNECT_TIMEOUT).await
+            }));
+        }
+        self.remove_peer(link);
+    }
+
+    /// Start a broadcast of our own.
+    async fn originate(&mut self, msg: M, priority: Priority) {
+        println!("Told to broadcast '{:?}'", msg);
+        let payload = Payload::Message(msg);
+        let mut seen = BloomFilter::new(self.config.path_filter);
+        seen.insert(&self.addr);
+
+        let op = Operation::Broadcast {
+            seen,
+            hops: 0, // ???
+        };
+
+        let mut packet = Packet::new(op, self.addr, payload);
+        packet.priority = priority;
+        // critical broadcasts are handed over the moment they arrive, they
+        // don't wait in line behind anything that went missing before them
+        if priority != Priority::Critical {
+            packet.seq = Some(self.next_broadcast_seq());
+            if self.config.causal {
+                packet.clock = Some(self.causal.stamp());
+            }
+        }
+        self.seen_msgs.insert(packet.id);
+        self.relay(packet, None).await;
+    }
+
+    async fn handle_packet(
+        &mut self,
+        from: SocketAddr,
+        pkt: Packet<M>,
+        datatx: &mpsc::Sender<(M, SocketAddr)>,
+    ) {
+        if let Some(peer) = self.peers.get_mut(&from) {
+            peer.last_heard = Instant::now();
+        }
+        if let Operation::Link = pkt.op {
+            // only the node itself talks over single links
+            if let Payload::Control(msg) = pkt.payload {
+                self.handle_link(from, msg).await;
+            }
             return;
-        } else {
-            self.seen_msgs.put(pkt.id, ());
+        }
+
+        if self.seen_msgs.outside_window(pkt.sent_at) {
+            // might have been seen and forgotten already, or will be by the
+            // time it's no longer "from the future", no way to tell
+            self.stats.stale_dropped += 1;
+            return;
+        }
+        if !self.seen_msgs.insert(pkt.id) {
+            self.stats.duplicates_dropped += 1;
+            // someone else got it to us first, this link isn't needed
+            if self.config.broadcast == BroadcastStrategy::Plumtree && self.plumtree.prune(from) {
+                self.send_link(from, ControlMsg::Gossip(GossipMsg::Prune))
+                    .await;
+            }
+            return;
+        }
+
+        // what kind of message operation was it? Pass it on first if need be
+        match &pkt.op {
+            Operation::Directed { target } => {
+                // directed packets that aren't for us just get passed along
+                if *target != self.addr {
+                    self.forward(pkt).await;
+                    return;
+                }
+            }
+            Operation::Broadcast { seen, hops } => {
+                // Duplicates were already caught by seen_msgs above, so the
+                // path filter is only used to avoid sending the packet back to
+                // nodes it has visited. A false positive on our own address
+                // must not stop us from delivering.
+                println!(
+                    "[{}] got msg {} '{:?}' {} hops",
+                    self.port, pkt.id, pkt.payload, hops
+                );
+                let mut seen = if seen.fits(self.config.path_filter) {
+                    seen.clone()
+                } else {
+                    // not one of ours, whatever it says can't be trusted
+                    BloomFilter::new(self.config.path_filter)
+                };
+                seen.insert(&self.addr);
+                let new_pkt = Packet {
+                    id: pkt.id,
+                    sender: pkt.sender,
+                    sent_at: pkt.sent_at,
+                    op: Operation::Broadcast {
+                        seen,
+                        hops: hops + 1,
+                    },
+                    seq: pkt.seq,
+                    clock: pkt.clock.clone(),
+                    priority: pkt.priority,
+                    payload: pkt.payload.clone(),
+                };
+                self.relay(new_pkt, Some(from)).await;
+            }
+            Operation::Topic { topic } => {
+                let subscribed = self.subs.is_subscribed(topic);
+                self.publish(pkt.clone(), Some(from)).await;
+                if !subscribed {
+                    return;
+                }
+            }
+            Operation::Link => unreachable!(),
         }
 
         match pkt.payload {
             Payload::Message(m) => {
-                // what kind of message operation was it?
-                match pkt.op {
-                    Operation::Broadcast { mut seen, hops } => {
-                        // TODO: get a real address and determine it earlier
-                        let my_addr = SocketAddr::from(([127, 0, 0, 1], self.port));
-                        // if I have already seen this message, skip it
-                        if seen.contains(&my_addr) {
-                            return;
-                        }
-                        println!("[{}] got msg {} '{:?}' {} hops", self.port, pkt.id, m, hops);
-                        seen.insert(my_addr);
-                        let new_pkt = Packet {
-                            id: pkt.id,
-                            sender: pkt.sender,
-                            op: Operation::Broadcast {
-                                seen,
-                                hops: hops + 1,
-                            },
-                            payload: Payload::Message(m.clone()),
-                        };
-                        self.broadcast(new_pkt).await;
+                self.deliver(m, pkt.sender, pkt.seq, pkt.clock, datatx)
+                    .await;
+            }
+            Payload::Reliable { id, msg } => {
+                if self.delivered.put(id, ()).is_none() {
+                    // the sender was promised this one gets through, so if
+                    // ordered delivery has already moved past it it's handed
+                    // over out of order rather than dropped as late
+                    let sender = pkt.sender;
+                    let seq = pkt.seq.filter(|seq| !self.reorder.is_late(&sender, seq));
+                    self.deliver(msg, pkt.sender, seq, None, datatx).await;
+                }
+                // always ack, our previous ack may be what got lost
+                self.send_directed(pkt.sender, Payload::Control(ControlMsg::Ack(id)))
+                    .await;
+            }
+            Payload::Control(ControlMsg::Ack(id)) => {
+                self.reliable.ack(&id, pkt.sender);
+            }
+            Payload::Request(m) => {
+                let sender = pkt.sender;
+                let reply = self.handler.as_mut().map(|handler| handler(sender, m));
+                self.send_directed(
+                    pkt.sender,
+                    Payload::Reply {
+                        to: pkt.id,
+                        msg: reply,
+                    },
+                )
+                .await;
+            }
+            Payload::Reply { to, msg } => {
+                self.calls
+                    .complete(&to, pkt.sender, msg.ok_or(RpcError::NoHandler));
+            }
+            Payload::Control(ControlMsg::Dht(msg)) if self.config.dht => {
+                let out = self.dht.handle(pkt.sender, msg);
+                self.send_dht(out).await;
+            }
+            Payload::Control(_) => {
+                // maintenance messages only make sense between neighbors
+            }
+        }
+    }
+
+    /// Hand an application message to `RunningNode::recv`, holding it back
+    /// first if ordered or causal delivery is on and earlier ones are still
+    /// missing.
+    async fn deliver(
+        &mut self,
+        msg: M,
+        origin: SocketAddr,
+        seq: Option<Seq>,
+        clock: Option<VectorClock>,
+        datatx: &mpsc::Sender<(M, SocketAddr)>,
+    ) {
+        let ready = match (seq, clock) {
+            (_, Some(clock)) if self.config.causal => self.causal.push(origin, clock, msg),
+            (Some(seq), _) if self.config.ordered => self
+                .reorder
+                .push(origin, seq, msg)
+                .into_iter()
+                .map(|m| (m, origin))
+                .collect(),
+            _ => vec![(msg, origin)],
+        };
+        for (msg, origin) in ready {
+            self.stats.messages_delivered += 1;
+            datatx.send((msg, origin)).await.expect("I am afraid");
+        }
+    }
+
+    /// Handle a control message that a neighbor addressed to us specifically.
+    async fn handle_link(&mut self, from: SocketAddr, msg: ControlMsg) {
+        match msg {
+            ControlMsg::Gossip(GossipMsg::IHave(ids)) => {
+                for id in ids {
+                    if !self.seen_msgs.contains(&id) {
+                        self.plumtree.ihave(id, from);
                     }
-                    Operation::Directed { .. } => {
-                        todo!("directed message!");
+                }
+            }
+            ControlMsg::Gossip(GossipMsg::Graft(id)) => {
+                if let Some(pkt) = self.plumtree.graft(&id, from) {
+                    self.send_to(from, &pkt).await;
+                }
+            }
+            ControlMsg::Gossip(GossipMsg::Prune) => {
+                self.plumtree.prune(from);
+            }
+            ControlMsg::Routes(entries) => {
+                // the name it gave in its hello, not whatever the packet says
+                if let Some(neighbor) = self.peers.get(&from).and_then(|p| p.node) {
+                    self.routes.update(from, neighbor, &entries);
+                }
+            }
+            // only pass on advertisements that were news to us
+            ControlMsg::LinkState(lsa) if self.linkstate.install(lsa.clone()) => {
+                self.flood_link(from, ControlMsg::LinkState(lsa)).await;
+            }
+            ControlMsg::Interest(interest) if self.subs.learn(&interest, from) => {
+                self.flood_link(from, ControlMsg::Interest(interest)).await;
+            }
+            ControlMsg::Summary { since, have } => {
+                let missing = self.history.missing(&have, since);
+                if !missing.is_empty() {
+                    println!(
+                        "[{}] replaying {} broadcasts to {}",
+                        self.port,
+                        missing.len(),
+                        from
+                    );
+                }
+                for pkt in missing {
+                    self.send_to(from, &pkt).await;
+                }
+            }
+            ControlMsg::Digest(theirs) => {
+                let buckets = self.history.differing(&theirs);
+                if !buckets.is_empty() {
+                    let ids = self.history.ids_in(&buckets);
+                    self.send_link(from, ControlMsg::DigestIds { buckets, ids })
+                        .await;
+                }
+            }
+            ControlMsg::DigestIds {
+                buckets,
+                ids: theirs,
+            } => {
+                let ours = self.history.ids_in(&buckets);
+                let wanted: Vec<_> = theirs
+                    .iter()
+                    .filter(|id| !ours.contains(*id) && !self.seen_msgs.contains(id))
+                    .copied()
+                    .collect();
+                if !wanted.is_empty() {
+                    self.send_link(from, ControlMsg::Pull(wanted)).await;
+                }
+                for id in ours.iter().filter(|id| !theirs.contains(id)) {
+                    if let Some(pkt) = self.history.get(id) {
+                        self.send_to(from, &pkt).await;
+                    }
+                }
+            }
+            ControlMsg::Pull(ids) => {
+                for id in ids {
+                    if let Some(pkt) = self.history.get(&id) {
+                        self.send_to(from, &pkt).await;
                     }
                 }
+            }
+            ControlMsg::Blob(msg) => {
+                let out = self.blobs.handle(from, msg);
+                self.send_blob(out).await;
+            }
+            ControlMsg::Ping(nonce) => {
+                self.send_link(from, ControlMsg::Pong(nonce)).await;
+            }
+            ControlMsg::Pong(nonce) => {
+                if let Some((sent_nonce, sent)) = self.pings.get(&from) {
+                    if *sent_nonce == nonce {
+                        let rtt = sent.elapsed();
+                        if let Some(peer) = self.peers.get_mut(&from) {
+                            if peer.rtt.is_none() {
+                                self.lsa_dirty = true;
+                            }
+                            peer.rtt = Some(rtt);
+                        }
+                        self.pings.remove(&from);
+                    }
+                }
+            }
+            ControlMsg::Heartbeat => {
+                // only here to bump last_heard
+            }
+            ControlMsg::Goodbye => {
+                println!("[{}] {} is leaving", self.port, from);
+                self.remove_peer(&from);
+            }
+            _ => {
+                // everything else travels end to end, not over a single link
+            }
+        }
+    }
+
+    async fn on_tick(&mut self, datatx: &mpsc::Sender<(M, SocketAddr)>) {
+        self.seen_msgs.expire();
+        while let Some(Some(_)) = self.tasks.next().now_or_never() {}
+
+        for (id, peer) in self.plumtree.expired() {
+            self.send_link(peer, ControlMsg::Gossip(GossipMsg::Graft(id)))
+                .await;
+        }
+
+        let mut ready = self.reorder.expire();
+        ready.extend(self.causal.expire());
+        for (msg, origin) in ready {
+            self.stats.messages_delivered += 1;
+            datatx.send((msg, origin)).await.expect("I am afraid");
+        }
+
+        self.calls.expire();
+        let out = self.blobs.poll();
+        self.send_blob(out).await;
+        for retry in self.reliable.due() {
+            self.retransmit(retry).await;
+        }
+
+        let out = self.dht.expire();
+        self.send_dht(out).await;
 
-                datatx.send((m, pkt.sender)).await.expect("I am afraid");
+        self.check_heartbeats().await;
+
+        if self.last_digest.elapsed() >= self.config.anti_entropy_interval {
+            self.last_digest = Instant::now();
+            let peer = self.peers.keys().copied().choose(&mut rand::thread_rng());
+            if let Some(peer) = peer {
+                let digest = self.history.digest();
+                self.send_link(peer, ControlMsg::Digest(digest)).await;
+            }
+        }
+
+        self.subs.expire();
+        if self.subs.has_local() && self.last_interest.elapsed() >= self.config.interest_interval {
+            let interest = self.subs.announce();
+            self.announce_interest(interest).await;
+        }
+
+        self.routes.expire();
+        self.linkstate.expire();
+        let triggered = self.routes.take_triggered() || self.lsa_dirty;
+        let periodic = self.last_advert.elapsed() >= self.config.route_interval;
+        if periodic && self.config.dht && self.dht.is_empty() {
+            // every contact timed out (or we never had any), start over
+            // from our neighbors
+            let neighbors: Vec<_> = self
+                .peers
+                .values()
+                .filter(|p| p.features.contains(&Feature::Dht))
+                .filter_map(|p| p.node)
+                .collect();
+            if !neighbors.is_empty() {
+                for node in neighbors {
+                    self.dht.observe(Contact::new(node));
+                }
+                let me = NodeKey::for_node(&self.addr);
+                let out = self.dht.lookup(me, Done::Bootstrap);
+                self.send_dht(out).await;
             }
         }
+        if periodic {
+            self.last_advert = Instant::now();
+            let peers: Vec<_> = self.peers.keys().copied().collect();
+            for peer in peers {
+                let nonce = rand::random();
+                self.pings.insert(peer, (nonce, Instant::now()));
+                self.send_link(peer, ControlMsg::Ping(nonce)).await;
+            }
+        }
+        if triggered || periodic {
+            match self.config.routing {
+                RoutingMode::DistanceVector => {
+                    let peers: Vec<_> = self.peers.keys().copied().collect();
+                    for peer in peers {
+                        let entries = self.routes.advertisement(&peer);
+                        self.send_link(peer, ControlMsg::Routes(entries)).await;
+                    }
+                }
+                RoutingMode::LinkState => self.advertise_links().await,
+            }
+        }
+    }
+
+    /// How long a link can be broken before we notice, in ms.
+    fn link_failure_ms(&self) -> u64 {
+        (self.config.heartbeat_interval * 3).as_millis() as u64
+    }
+
+    /// Drop neighbors that have gone quiet, and let the others know we're
+    /// still here if we haven't had anything else to tell them.
+    async fn check_heartbeats(&mut self) {
+        let interval = self.config.heartbeat_interval;
+        let silent: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.last_heard.elapsed() >= interval * 3)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in silent {
+            println!("[{}] {} went quiet", self.port, addr);
+            self.remove_peer(&addr);
+        }
+        let idle: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.last_sent.elapsed() >= interval)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in idle {
+            self.send_link(addr, ControlMsg::Heartbeat).await;
+        }
+    }
+
+    /// Flood a new link state advertisement describing our current peers.
+    async fn advertise_links(&mut self) {
+        self.lsa_dirty = false;
+        self.lsa_seq += 1;
+        let links = self
+            .peers
+            .values()
+            .filter_map(|p| Some((p.node?, p.rtt?.as_millis().min(u32::MAX as u128) as u32)))
+            .collect();
+        let lsa = LinkStateAdvert {
+            origin: self.addr,
+            seq: self.lsa_seq,
+            links,
+        };
+        self.linkstate.install(lsa.clone());
+        let peers: Vec<_> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.send_link(peer, ControlMsg::LinkState(lsa.clone()))
+                .await;
+        }
+    }
+
+    /// The peer link we're connected to `node` on, if it is a neighbor.
+    fn link_for(&self, node: &SocketAddr) -> Option<SocketAddr> {
+        self.peers
+            .iter()
+            .find(|(_, p)| p.node.as_ref() == Some(node))
+            .map(|(addr, _)| *addr)
+    }
+
+    fn next_hop(&self, target: &SocketAddr) -> Option<SocketAddr> {
+        let routed = match self.config.routing {
+            RoutingMode::DistanceVector => self.routes.next_hop(target),
+            RoutingMode::LinkState => self
+                .linkstate
+                .first_hop(target)
+                .and_then(|hop| self.link_for(&hop)),
+        };
+        // neighbors are reachable before the routing protocol catches up
+        routed.or_else(|| self.link_for(target))
+    }
+
+    fn route_list(&self) -> Vec<Route> {
+        match self.config.routing {
+            RoutingMode::DistanceVector => self.routes.routes(),
+            RoutingMode::LinkState => self.linkstate.routes(|node| self.link_for(node)),
+        }
+    }
+
+    fn next_broadcast_seq(&mut self) -> Seq {
+        self.broadcast_seq += 1;
+        Seq {
+            epoch: self.epoch,
+            n: self.broadcast_seq,
+        }
+    }
+
+    /// Resend a reliable message straight to a node that hasn't acked it. The
+    /// first retry follows the routing table, later ones go out through each of
+    /// our other neighbors in turn in case the usual path is what's broken.
+    async fn retransmit(&mut self, retry: Retry<M>) {
+        let op = Operation::Directed {
+            target: retry.target,
+        };
+        let payload = Payload::Reliable {
+            id: retry.id,
+            msg: retry.msg,
+        };
+        let mut packet = Packet::new(op, self.addr, payload);
+        packet.seq = retry.seq;
+        self.seen_msgs.insert(packet.id);
+
+        let routed = self.next_hop(&retry.target);
+        let mut others: Vec<_> = self
+            .peers
+            .keys()
+            .filter(|p| Some(**p) != routed)
+            .copied()
+            .collect();
+        others.sort();
+        let via = if retry.attempt == 1 || others.is_empty() {
+            routed
+        } else {
+            Some(others[retry.attempt % others.len()])
+        };
+        if let Some(via) = via {
+            self.send_to(via, &packet).await;
+        }
+    }
+
+    /// Send a published message towards every subscriber of its topic.
+    async fn publish(&mut self, pkt: Packet<M>, from: Option<SocketAddr>) {
+        let topic = match &pkt.op {
+            Operation::Topic { topic } => topic,
+            _ => unreachable!("only topic packets are published"),
+        };
+        for peer in self.subs.next_hops(topic, from) {
+            self.send_to(peer, &pkt).await;
+        }
+    }
+
+    async fn announce_interest(&mut self, interest: Interest) {
+        self.last_interest = Instant::now();
+        let peers: Vec<_> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.send_link(peer, ControlMsg::Interest(interest.clone()))
+                .await;
+        }
+    }
+
+    /// Send a packet to a node that isn't necessarily a neighbor.
+    async fn send_directed(&mut self, target: SocketAddr, payload: Payload<M>) {
+        let op = Operation::Directed { target };
+        let packet = Packet::new(op, self.addr, payload);
+        self.seen_msgs.insert(packet.id);
+        self.forward(packet).await;
+    }
+
+    async fn send_blob(&mut self, out: Vec<(SocketAddr, BlobMsg)>) {
+        for (peer, msg) in out {
+            self.send_link(peer, ControlMsg::Blob(msg)).await;
+        }
+    }
+
+    async fn send_dht(&mut self, out: Vec<(SocketAddr, DhtMsg)>) {
+        for (target, msg) in out {
+            self.send_directed(target, Payload::Control(ControlMsg::Dht(msg)))
+                .await;
+        }
+    }
+
+    /// Send a directed packet one hop closer to its target.
+    async fn forward(&mut self, pkt: Packet<M>) {
+        let target = match pkt.op {
+            Operation::Directed { target } => target,
+            _ => unreachable!("only directed packets are forwarded"),
+        };
+        match self.next_hop(&target) {
+            Some(next_hop) => {
+                self.send_to(next_hop, &pkt).await;
+            }
+            None => println!(
+                "[{}] no route to {}, dropping {}",
+                self.port, target, pkt.id
+            ),
+        }
     }
 
     pub fn start(self) -> RunningNode<M> {
         let (metatx, metarx) = mpsc::channel(META_CHAN_CAPACITY);
+        let (alarms, alarmrx) = mpsc::channel(META_CHAN_CAPACITY);
         let (datatx, datarx) = mpsc::channel(MSG_CHAN_CAPACITY);
-        let handle = tokio::spawn(self.run(metarx, datatx));
+        let addr = self.addr;
+        let handle = tokio::spawn(self.run(metarx, alarmrx, datatx));
         RunningNode {
+            addr,
             handle,
             tx: metatx,
+            alarms,
             rx: datarx,
         }
     }
 
+    /// Pass a broadcast on to our neighbors (other than the one it came from)
+    /// according to the configured strategy.
+    async fn relay(&mut self, pkt: Packet<M>, from: Option<SocketAddr>) {
+        self.history.record(pkt.clone());
+        match self.config.broadcast {
+            BroadcastStrategy::Flood => {
+                for addr in self.broadcast(pkt).await.keys() {
+                    self.remove_peer(addr);
+                }
+            }
+            BroadcastStrategy::Plumtree => {
+                self.plumtree.received(&pkt, from);
+                let eager: Vec<_> = self
+                    .plumtree
+                    .eager_peers()
+                    .filter(|p| Some(**p) != from)
+                    .copied()
+                    .collect();
+                let lazy: Vec<_> = self
+                    .plumtree
+                    .lazy_peers()
+                    .filter(|p| Some(**p) != from)
+                    .copied()
+                    .collect();
+                for peer in eager {
+                    self.send_to(peer, &pkt).await;
+                }
+                for peer in lazy {
+                    self.send_link(peer, ControlMsg::Gossip(GossipMsg::IHave(vec![pkt.id])))
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Send a packet to a single neighbor, dropping the peer if that fails.
+    async fn send_to(&mut self, addr: SocketAddr, pkt: &Packet<M>) -> Option<tokio::io::Error> {
+        let peer = self.peers.get_mut(&addr)?;
+        self.stats.packets_sent += 1;
+        let err = peer.send_packet(pkt).await.err();
+        if err.is_some() {
+            self.remove_peer(&addr);
+        }
+        err
+    }
+
+    /// Send a link packet to every neighbor except `except`.
+    async fn flood_link(&mut self, except: SocketAddr, msg: ControlMsg) {
+        let peers: Vec<_> = self
+            .peers
+            .keys()
+            .filter(|p| **p != except)
+            .copied()
+            .collect();
+        for peer in peers {
+            self.send_link(peer, msg.clone()).await;
+        }
+    }
+
+    async fn send_link(&mut self, addr: SocketAddr, msg: ControlMsg) -> Option<tokio::io::Error> {
+        let pkt = Packet::new(Operation::Link, self.addr, Payload::Control(msg));
+        self.send_to(addr, &pkt).await
+    }
+
     /// Send a msg to each node in the peer set and returns a set of
+    /// the errors that occurred, keyed by peer. Peers already recorded in a
+    /// broadcast's path filter are skipped, as long as the filter isn't too
+    /// full to go by.
     pub async fn broadcast(&mut self, payload: Packet<M>) -> HashMap<SocketAddr, tokio::io::Error> {
         let mut errs = HashMap::new();
+        let seen = match &payload.op {
+            Operation::Broadcast { seen, .. }
+                if seen.false_positive_rate() <= MAX_PATH_FILTER_FP =>
+            {
+                Some(seen)
+            }
+            _ => None,
+        };
         for (addr, peer) in &mut self.peers {
+            // the filter has node addresses, accepted links go by another
+            if seen.is_some_and(|seen| seen.contains(&peer.node.unwrap_or(*addr))) {
+                continue;
+            }
+            self.stats.packets_sent += 1;
             if let Err(e) = peer.send_packet(&payload).await {
                 errs.insert(*addr, e);
             }
@@ -173,37 +1473,329 @@ impl<M: SanePayload> Node<M> {
     }
 }
 
+/// The node shut down before an operation it was running could finish.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NodeStopped;
+
+impl fmt::Display for NodeStopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "node stopped")
+    }
+}
+
+impl std::error::Error for NodeStopped {}
+
+/// Counters for what a node has been up to, mostly useful for comparing the
+/// overhead of the different broadcast strategies.
+#[derive(Clone, Debug, Default)]
+pub struct NodeStats {
+    /// Every packet written to a peer, including forwarded and control ones.
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Application messages handed to `RunningNode::recv`.
+    pub messages_delivered: u64,
+    /// Broadcasts dropped by ordered delivery because they showed up after
+    /// it had stopped waiting for them.
+    pub messages_late: u64,
+    /// Packets we'd already seen, dropped by duplicate suppression.
+    pub duplicates_dropped: u64,
+    /// Packets dropped for being older than the dedup window, or dated too
+    /// far in the future.
+    pub stale_dropped: u64,
+    /// Frames thrown away for going over a rate limit.
+    pub rate_limited: u64,
+    /// Neighbors hung up on for going over their rate limit.
+    pub rate_limit_disconnects: u64,
+}
+
 pub enum MetaCommand<M> {
     Die,
-    Broadcast(M),
+    /// Leave the network cleanly, see `RunningNode::shutdown`.
+    Shutdown(Duration),
+    Broadcast(M, Priority),
     AddPeer(TcpStream, SocketAddr),
+    /// Dial a node and make it a peer.
+    Connect(SocketAddr, ConnectReply),
+    /// Hang up on a neighbor, by link or node address.
+    Disconnect(SocketAddr, oneshot::Sender<bool>),
+    /// Drop any links to a node and refuse it from now on.
+    Ban(SocketAddr),
+    Unban(SocketAddr),
+    /// Drop any links to a host and refuse it from now on.
+    BanIp(IpAddr),
+    UnbanIp(IpAddr),
+    Peers(oneshot::Sender<Vec<PeerInfo>>),
+    /// Send a message to a single node, wherever it is in the network.
+    SendTo(SocketAddr, M),
+    /// Broadcast a message and keep resending it until every target acks it
+    /// or the timeout passes. An empty target list means every known node.
+    BroadcastReliable(
+        M,
+        Vec<SocketAddr>,
+        Duration,
+        oneshot::Sender<Result<DeliveryReport, DeliveryError>>,
+    ),
+    Request(
+        SocketAddr,
+        M,
+        Duration,
+        oneshot::Sender<Result<M, RpcError>>,
+    ),
+    SetHandler(RequestHandler<M>),
+    Publish(String, M),
+    Subscribe(String),
+    Unsubscribe(String),
+    Stats(oneshot::Sender<NodeStats>),
+    Routes(oneshot::Sender<Vec<Route>>),
+    Topology(oneshot::Sender<Vec<LinkStateAdvert>>),
+    DhtPut(Vec<u8>, Vec<u8>, oneshot::Sender<Result<usize, DhtError>>),
+    DhtGet(Vec<u8>, oneshot::Sender<Result<Option<Vec<u8>>, DhtError>>),
+    DhtFindNode(NodeKey, oneshot::Sender<Result<Vec<Contact>, DhtError>>),
+    BlobPut(Vec<u8>, oneshot::Sender<BlobId>),
+    BlobGet(
+        BlobId,
+        Duration,
+        oneshot::Sender<Result<Vec<u8>, BlobError>>,
+    ),
 }
 
 pub struct RunningNode<M> {
+    addr: SocketAddr,
     handle: tokio::task::JoinHandle<()>,
     tx: mpsc::Sender<MetaCommand<M>>,
+    /// Critical broadcasts, so they don't wait behind other commands.
+    alarms: mpsc::Sender<M>,
     rx: mpsc::Receiver<(M, SocketAddr)>,
 }
 
 impl<M: SanePayload> RunningNode<M> {
+    /// The address this node goes by, which other nodes dial.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
     pub async fn wait(self) {
         self.handle.await.unwrap();
     }
 
+    /// Leave the network cleanly: stop accepting connections, send each
+    /// neighbor whatever is still queued for it followed by a goodbye (so it
+    /// drops us straight away instead of waiting for the link to go quiet),
+    /// then close the connection. Anything that isn't out within `timeout`
+    /// is given up on. Returns once the node and all its link tasks are done.
+    pub async fn shutdown(self, timeout: Duration) {
+        let _ = self.tx.send(MetaCommand::Shutdown(timeout)).await;
+        self.wait().await;
+    }
+
     pub async fn terminate(self) {
         let _ = self.tx.send(MetaCommand::Die).await;
         self.wait().await;
     }
 
     pub async fn broadcast(&mut self, msg: M) {
-        let _ = self.tx.send(MetaCommand::Broadcast(msg)).await;
+        self.broadcast_with_priority(msg, Priority::Normal).await;
+    }
+
+    /// Broadcast `msg` at `priority`. Critical broadcasts overtake everything
+    /// queued on the links they cross and in the nodes they pass through,
+    /// bulk ones wait for everything else.
+    pub async fn broadcast_with_priority(&mut self, msg: M, priority: Priority) {
+        if priority == Priority::Critical {
+            let _ = self.alarms.send(msg).await;
+        } else {
+            let _ = self.tx.send(MetaCommand::Broadcast(msg, priority)).await;
+        }
     }
 
     pub async fn send_cmd(&mut self, cmd: MetaCommand<M>) {
         let _ = self.tx.send(cmd).await;
     }
 
+    /// Dial `addr` and make it a peer. Returns the node's address once it has
+    /// said hello.
+    pub async fn connect(&mut self, addr: SocketAddr) -> Result<SocketAddr, HandshakeError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::Connect(addr, tx)).await;
+        rx.await
+            .unwrap_or_else(|_| Err(HandshakeError::Io(io::Error::other("node stopped"))))
+    }
+
+    /// Say goodbye to `peer`, a link or node address as in `PeerInfo`, and
+    /// hang up. False if it wasn't a neighbor.
+    pub async fn disconnect(&mut self, peer: SocketAddr) -> bool {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::Disconnect(peer, tx)).await;
+        rx.await.unwrap_or(false)
+    }
+
+    /// Hang up on `node` if it's a neighbor, and refuse to connect to it
+    /// either way until it's unbanned.
+    pub async fn ban(&mut self, node: SocketAddr) {
+        let _ = self.tx.send(MetaCommand::Ban(node)).await;
+    }
+
+    pub async fn unban(&mut self, node: SocketAddr) {
+        let _ = self.tx.send(MetaCommand::Unban(node)).await;
+    }
+
+    /// Like `ban`, but for every link to or from `ip`. Nodes name themselves
+    /// in their hellos, so one that's been banned can come back under
+    /// another name, but not from another host.
+    pub async fn ban_ip(&mut self, ip: IpAddr) {
+        let _ = self.tx.send(MetaCommand::BanIp(ip)).await;
+    }
+
+    pub async fn unban_ip(&mut self, ip: IpAddr) {
+        let _ = self.tx.send(MetaCommand::UnbanIp(ip)).await;
+    }
+
+    /// Our neighbors, and what's been over the links to them.
+    pub async fn peers(&mut self) -> Vec<PeerInfo> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::Peers(tx)).await;
+        rx.await.unwrap_or_default()
+    }
+
+    /// Broadcast `msg` and wait until every node in `targets` (every node we
+    /// have a route to, if empty) has acknowledged it, or `timeout` passes.
+    /// Unacknowledged targets get the message resent to them directly. Fails
+    /// if `targets` is empty and we have no routes.
+    pub async fn broadcast_reliable(
+        &mut self,
+        msg: M,
+        targets: Vec<SocketAddr>,
+        timeout: Duration,
+    ) -> Result<DeliveryReport, DeliveryError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .tx
+            .send(MetaCommand::BroadcastReliable(msg, targets, timeout, tx))
+            .await;
+        rx.await.map_err(|_| NodeStopped)?
+    }
+
+    /// Ask `target` something and wait for its answer, for at most `timeout`.
+    pub async fn request(
+        &mut self,
+        target: SocketAddr,
+        msg: M,
+        timeout: Duration,
+    ) -> Result<M, RpcError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .tx
+            .send(MetaCommand::Request(target, msg, timeout, tx))
+            .await;
+        rx.await.map_err(|_| NodeStopped)?
+    }
+
+    /// Answer requests from other nodes with `handler`, replacing any
+    /// previous one. Without a handler, requesters get `RpcError::NoHandler`.
+    pub async fn on_request(&mut self, handler: impl FnMut(SocketAddr, M) -> M + Send + 'static) {
+        let _ = self
+            .tx
+            .send(MetaCommand::SetHandler(Box::new(handler)))
+            .await;
+    }
+
+    /// Start receiving messages published on `topic` (through `recv`).
+    pub async fn subscribe(&mut self, topic: &str) {
+        let _ = self.tx.send(MetaCommand::Subscribe(topic.to_owned())).await;
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) {
+        let _ = self
+            .tx
+            .send(MetaCommand::Unsubscribe(topic.to_owned()))
+            .await;
+    }
+
+    /// Send a message to every node subscribed to `topic`. It only travels
+    /// down links that lead to a subscriber.
+    pub async fn publish(&mut self, topic: &str, msg: M) {
+        let _ = self
+            .tx
+            .send(MetaCommand::Publish(topic.to_owned(), msg))
+            .await;
+    }
+
+    pub async fn send_to(&mut self, target: SocketAddr, msg: M) {
+        let _ = self.tx.send(MetaCommand::SendTo(target, msg)).await;
+    }
+
+    /// A snapshot of this node's routing table.
+    pub async fn routes(&mut self) -> Vec<Route> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::Routes(tx)).await;
+        rx.await.unwrap_or_default()
+    }
+
+    /// Every link state advertisement this node knows about, i.e. the
+    /// network topology as it sees it. Empty unless running link state.
+    pub async fn topology(&mut self) -> Vec<LinkStateAdvert> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::Topology(tx)).await;
+        rx.await.unwrap_or_default()
+    }
+
+    /// Store a small record in the DHT, on the `K` nodes closest to the hash
+    /// of `key`. Returns how many of them confirmed they kept it.
+    pub async fn dht_put(&mut self, key: &[u8], value: Vec<u8>) -> Result<usize, DhtError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .tx
+            .send(MetaCommand::DhtPut(key.to_vec(), value, tx))
+            .await;
+        rx.await.map_err(|_| NodeStopped)?
+    }
+
+    /// Look up the record stored under `key`, `None` if nobody has it.
+    pub async fn dht_get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DhtError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::DhtGet(key.to_vec(), tx)).await;
+        rx.await.map_err(|_| NodeStopped)?
+    }
+
+    /// The `K` live nodes closest to `target` that an iterative lookup found.
+    pub async fn dht_find_node(&mut self, target: NodeKey) -> Result<Vec<Contact>, DhtError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::DhtFindNode(target, tx)).await;
+        rx.await.map_err(|_| NodeStopped)?
+    }
+
+    /// Add a blob to this node's store, so neighbors can fetch it. Returns
+    /// the id to fetch it by.
+    pub async fn put_blob(&mut self, data: Vec<u8>) -> Option<BlobId> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::BlobPut(data, tx)).await;
+        rx.await.ok()
+    }
+
+    /// Get blob `id`, in chunks from whichever neighbors have it, checking
+    /// each one against the blob's manifest. A fetch that times out keeps
+    /// what it got, so trying again resumes the download. Once we have the
+    /// blob our neighbors can fetch it from us, which is how it spreads.
+    pub async fn fetch_blob(
+        &mut self,
+        id: BlobId,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, BlobError> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::BlobGet(id, timeout, tx)).await;
+        rx.await.map_err(|_| NodeStopped)?
+    }
+
+    pub async fn stats(&mut self) -> Option<NodeStats> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.tx.send(MetaCommand::Stats(tx)).await;
+        rx.await.ok()
+    }
+
     pub async fn recv(&mut self) -> Option<(M, SocketAddr)> {
         self.rx.recv().await
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,822 @@
+//! Whole nodes talking over real sockets on localhost. Every node gets a free
+//! port, so these can run side by side.
+
+use super::*;
+
+/// Wait for `$cond` to hold, checking again every 10ms, for up to 5s.
+macro_rules! eventually {
+    ($cond:expr) => {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut every = tokio::time::interval(Duration::from_millis(10));
+            while !$cond {
+                every.tick().await;
+            }
+        })
+        .await
+        .expect(concat!("gave up waiting for ", stringify!($cond)))
+    };
+}
+
+/// A config that keeps quiet: only what a test sets off goes over the links.
+fn quiet() -> NodeConfig {
+    NodeConfig {
+        route_interval: Duration::from_secs(60),
+        heartbeat_interval: Duration::from_secs(60),
+        anti_entropy_interval: Duration::from_secs(60),
+        compression: vec![],
+        ..Default::default()
+    }
+}
+
+/// Start `n` nodes and link them up along `edges`, by index.
+async fn mesh(n: usize, edges: &[(usize, usize)], config: NodeConfig) -> Vec<RunningNode<String>> {
+    let mut nodes = Vec::new();
+    for _ in 0..n {
+        nodes.push(Node::with_config(0, config.clone()).await.start());
+    }
+    let mut links = vec![0; n];
+    for (a, b) in edges {
+        let addr = nodes[*b].addr();
+        nodes[*a]
+            .connect(addr)
+            .await
+            .expect("nodes on localhost connect");
+        links[*a] += 1;
+        links[*b] += 1;
+    }
+    // the dialed ends finish their side of the handshakes too
+    for (node, links) in nodes.iter_mut().zip(links) {
+        eventually!(node.peers().await.len() == links);
+    }
+    nodes
+}
+
+/// Every pair of `n` nodes linked.
+fn complete(n: usize) -> Vec<(usize, usize)> {
+    (0..n)
+        .flat_map(|a| (a + 1..n).map(move |b| (a, b)))
+        .collect()
+}
+
+async fn recv(node: &mut RunningNode<String>) -> Option<(String, SocketAddr)> {
+    tokio::time::timeout(Duration::from_secs(5), node.recv())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Wait until nothing is on its way over any link: everything written has
+/// been read at the other end, and nothing more was written since last time.
+async fn idle(nodes: &mut [RunningNode<String>]) {
+    let mut last = None;
+    eventually!({
+        let (mut sent, mut received) = (0, 0);
+        for node in nodes.iter_mut() {
+            for peer in node.peers().await {
+                sent += peer.bytes_sent;
+                received += peer.bytes_received;
+            }
+        }
+        let done = sent == received && last == Some(sent);
+        last = Some(sent);
+        done
+    });
+}
+
+/// Whether `node` has a route to `dest` that isn't poisoned.
+async fn routes_to(node: &mut RunningNode<String>, dest: SocketAddr) -> bool {
+    node.routes()
+        .await
+        .iter()
+        .any(|r| r.dest == dest && r.hops < crate::routing::INFINITY)
+}
+
+async fn packets_received(node: &mut RunningNode<String>) -> u64 {
+    node.stats().await.unwrap().packets_received
+}
+
+/// Bytes written and duplicates thrown away across the whole mesh.
+async fn overhead(nodes: &mut [RunningNode<String>]) -> (u64, u64) {
+    let mut bytes = 0;
+    let mut duplicates = 0;
+    for node in nodes {
+        bytes += node.peers().await.iter().map(|p| p.bytes_sent).sum::<u64>();
+        duplicates += node.stats().await.unwrap().duplicates_dropped;
+    }
+    (bytes, duplicates)
+}
+
+/// Broadcast `count` messages of `size` bytes from node 0 on a complete mesh
+/// that has seen a few broadcasts already, and measure what that cost.
+async fn broadcast_overhead(strategy: BroadcastStrategy, count: usize, size: usize) -> (u64, u64) {
+    let config = NodeConfig {
+        broadcast: strategy,
+        ..quiet()
+    };
+    let mut nodes = mesh(6, &complete(6), config).await;
+    // let the tree prune itself
+    for i in 0..3 {
+        nodes[0].broadcast(format!("warm up {}", i)).await;
+        for node in &mut nodes[1..] {
+            recv(node).await.expect("warm up delivered");
+        }
+    }
+    idle(&mut nodes).await;
+
+    let before = overhead(&mut nodes).await;
+    for i in 0..count {
+        let msg = format!("{:>width$}", i, width = size);
+        nodes[0].broadcast(msg).await;
+        for node in &mut nodes[1..] {
+            recv(node).await.expect("delivered");
+        }
+    }
+    idle(&mut nodes).await;
+    let after = overhead(&mut nodes).await;
+    (after.0 - before.0, after.1 - before.1)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn plumtree_costs_less_than_flooding() {
+    let (flood_bytes, flood_dups) = broadcast_overhead(BroadcastStrategy::Flood, 10, 2000).await;
+    let (tree_bytes, tree_dups) = broadcast_overhead(BroadcastStrategy::Plumtree, 10, 2000).await;
+    println!(
+        "flood: {} bytes, {} duplicates. plumtree: {} bytes, {} duplicates",
+        flood_bytes, flood_dups, tree_bytes, tree_dups
+    );
+    // flooding sends each message over (nearly) every one of the 15 links,
+    // the tree over the 5 that span the mesh plus an IHAVE on the others
+    assert!(flood_dups >= 10 * 10);
+    assert!(tree_dups <= 10);
+    assert!(tree_bytes * 2 < flood_bytes);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn link_state_routes_carry_hops_and_cost() {
+    let config = NodeConfig {
+        routing: RoutingMode::LinkState,
+        route_interval: Duration::from_millis(100),
+        ..quiet()
+    };
+    let mut nodes = mesh(3, &[(0, 1), (1, 2)], config).await;
+    let far = nodes[2].addr();
+    eventually!(routes_to(&mut nodes[0], far).await);
+    let route = nodes[0]
+        .routes()
+        .await
+        .into_iter()
+        .find(|r| r.dest == far)
+        .expect("a route across the middle node");
+    assert_eq!(route.hops, 2);
+    assert!(route.cost.is_some());
+    assert_eq!(nodes[0].topology().await.len(), 3);
+}
+
+/// Six nodes in a ring with a couple of chords, running the DHT.
+async fn dht_mesh() -> Vec<RunningNode<String>> {
+    let config = NodeConfig {
+        dht: true,
+        dht_timeout: Duration::from_millis(500),
+        route_interval: Duration::from_millis(100),
+        ..quiet()
+    };
+    let edges = [(0, 1), (1, 2), (2, 3), (3, 4), (4, 5), (5, 0), (0, 3)];
+    let mut nodes = mesh(6, &edges, config).await;
+    // routes to everyone, so DHT queries can go anywhere
+    let everyone: Vec<_> = nodes.iter().map(|n| n.addr()).collect();
+    for node in &mut nodes {
+        for dest in &everyone {
+            eventually!(*dest == node.addr() || routes_to(node, *dest).await);
+        }
+    }
+    nodes
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dht_records_can_be_found_from_any_node() {
+    let mut nodes = dht_mesh().await;
+    let stored = nodes[0].dht_put(b"config", b"v1".to_vec()).await;
+    // fewer than K nodes, so every one of them holds a copy
+    assert_eq!(stored, Ok(6));
+    for node in &mut nodes[1..] {
+        assert_eq!(node.dht_get(b"config").await, Ok(Some(b"v1".to_vec())));
+    }
+    assert_eq!(nodes[4].dht_get(b"nothing here").await, Ok(None));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dht_lookups_work_around_a_node_that_left() {
+    let mut nodes = dht_mesh().await;
+    assert_eq!(nodes[1].dht_put(b"presence", b"here".to_vec()).await, Ok(6));
+    let gone = nodes.remove(4);
+    let gone_addr = gone.addr();
+    gone.shutdown(Duration::from_secs(1)).await;
+    for node in &mut nodes {
+        eventually!(!node.peers().await.iter().any(|p| p.node == Some(gone_addr)));
+    }
+
+    let found = nodes[2]
+        .dht_find_node(NodeKey::for_node(&gone_addr))
+        .await
+        .unwrap();
+    assert!(!found.is_empty());
+    assert!(found.iter().all(|c| c.addr != gone_addr));
+    assert_eq!(
+        nodes[0].dht_get(b"presence").await,
+        Ok(Some(b"here".to_vec()))
+    );
+    assert_eq!(
+        nodes[3].dht_put(b"presence", b"moved".to_vec()).await,
+        Ok(5)
+    );
+    assert_eq!(
+        nodes[2].dht_get(b"presence").await,
+        Ok(Some(b"moved".to_vec()))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dht_calls_fail_when_the_dht_is_off() {
+    let mut nodes = mesh(2, &[(0, 1)], quiet()).await;
+    let stored = nodes[0].dht_put(b"key", b"value".to_vec()).await;
+    assert_eq!(stored, Err(DhtError::Disabled));
+    assert_eq!(nodes[0].dht_get(b"key").await, Err(DhtError::Disabled));
+    let found = nodes[0].dht_find_node(NodeKey::hash(b"key")).await;
+    assert_eq!(found, Err(DhtError::Disabled));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn published_messages_reach_only_subscribers() {
+    // 0 - 1 - 2, with 3 hanging off 1
+    let mut nodes = mesh(4, &[(0, 1), (1, 2), (1, 3)], quiet()).await;
+    // the interest goes out to everyone
+    let before = packets_received(&mut nodes[0]).await;
+    nodes[2].subscribe("news").await;
+    eventually!(packets_received(&mut nodes[0]).await > before);
+    nodes[0].publish("news", "extra extra".to_owned()).await;
+    let (msg, origin) = recv(&mut nodes[2]).await.expect("subscriber gets it");
+    assert_eq!((msg.as_str(), origin), ("extra extra", nodes[0].addr()));
+
+    // neither the node in the middle nor the one off to the side see it, the
+    // next thing they get is a broadcast sent after it
+    nodes[0].broadcast("to all".to_owned()).await;
+    for i in [1, 2, 3] {
+        let (msg, _) = recv(&mut nodes[i]).await.unwrap();
+        assert_eq!(
+            msg, "to all",
+            "node {} got a message it didn't subscribe to",
+            i
+        );
+    }
+
+    let before = packets_received(&mut nodes[0]).await;
+    nodes[2].unsubscribe("news").await;
+    eventually!(packets_received(&mut nodes[0]).await > before);
+    nodes[0].publish("news", "old news".to_owned()).await;
+    nodes[0].broadcast("to all".to_owned()).await;
+    assert_eq!(recv(&mut nodes[2]).await.unwrap().0, "to all");
+}
+
+/// A line of three nodes with routes along it.
+async fn routed_line() -> Vec<RunningNode<String>> {
+    let config = NodeConfig {
+        route_interval: Duration::from_millis(100),
+        ..quiet()
+    };
+    let mut nodes = mesh(3, &[(0, 1), (1, 2)], config).await;
+    let (near, far) = (nodes[0].addr(), nodes[2].addr());
+    eventually!(routes_to(&mut nodes[0], far).await);
+    eventually!(routes_to(&mut nodes[2], near).await);
+    nodes
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_are_answered_across_the_mesh() {
+    let mut nodes = routed_line().await;
+    let far = nodes[2].addr();
+    let me = nodes[0].addr();
+    let timeout = Duration::from_secs(2);
+    let reply = nodes[0].request(far, "ping".to_owned(), timeout).await;
+    assert_eq!(reply, Err(RpcError::NoHandler));
+
+    nodes[2]
+        .on_request(move |from, msg| format!("{} from {}", msg, from))
+        .await;
+    let reply = nodes[0].request(far, "ping".to_owned(), timeout).await;
+    assert_eq!(reply, Ok(format!("ping from {}", me)));
+
+    let nowhere = SocketAddr::from(([127, 0, 0, 1], 1));
+    let reply = nodes[0].request(nowhere, "ping".to_owned(), timeout).await;
+    assert_eq!(reply, Err(RpcError::NoRoute));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reliable_broadcasts_are_confirmed_by_everyone() {
+    let mut nodes = routed_line().await;
+    let everyone: HashSet<_> = nodes[1..].iter().map(|n| n.addr()).collect();
+    let report = nodes[0]
+        .broadcast_reliable("hi".to_owned(), vec![], Duration::from_secs(2))
+        .await
+        .expect("there are routes to deliver along");
+    assert_eq!(report.confirmed, everyone);
+    assert!(report.missing.is_empty());
+    for node in &mut nodes[1..] {
+        assert_eq!(recv(node).await.unwrap().0, "hi");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reliable_broadcasts_with_nobody_to_reach_fail() {
+    let mut node = Node::with_config(0, quiet()).await.start();
+    let report = node
+        .broadcast_reliable("hi".to_owned(), vec![], Duration::from_secs(1))
+        .await;
+    assert_eq!(report.unwrap_err(), DeliveryError::NoTargets);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ordered_delivery_survives_the_origin_restarting() {
+    let config = NodeConfig {
+        ordered: true,
+        ..quiet()
+    };
+    let mut nodes = mesh(2, &[(0, 1)], config.clone()).await;
+    for i in 0..3 {
+        nodes[0].broadcast(format!("before {}", i)).await;
+        assert_eq!(
+            recv(&mut nodes[1]).await.unwrap().0,
+            format!("before {}", i)
+        );
+    }
+
+    let origin = nodes.remove(0);
+    let port = origin.addr().port();
+    origin.shutdown(Duration::from_secs(1)).await;
+    let restarted = Instant::now();
+    let mut origin = Node::with_config(port, config).await.start();
+    origin.connect(nodes[0].addr()).await.unwrap();
+    eventually!(nodes[0]
+        .peers()
+        .await
+        .iter()
+        .any(|p| p.connected_since >= restarted));
+
+    // counting from 1 again must not look like old news
+    for i in 0..3 {
+        origin.broadcast(format!("after {}", i)).await;
+        assert_eq!(recv(&mut nodes[0]).await.unwrap().0, format!("after {}", i));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn new_neighbors_are_not_replayed_old_broadcasts() {
+    let mut nodes = mesh(2, &[(0, 1)], quiet()).await;
+    for i in 0..3 {
+        nodes[0].broadcast(format!("old {}", i)).await;
+        recv(&mut nodes[1]).await.unwrap();
+    }
+    // broadcasts from the very moment a node starts count as news to it
+    let sent = now_millis();
+    eventually!(now_millis() > sent);
+    let mut fresh: RunningNode<String> = Node::with_config(0, quiet()).await.start();
+    fresh.connect(nodes[0].addr()).await.unwrap();
+    nodes[0].broadcast("new".to_owned()).await;
+    assert_eq!(recv(&mut fresh).await.unwrap().0, "new");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn neighbors_that_were_away_get_what_they_missed() {
+    let mut nodes = mesh(2, &[(0, 1)], quiet()).await;
+    nodes[0].broadcast("before".to_owned()).await;
+    recv(&mut nodes[1]).await.unwrap();
+
+    let hub = nodes[0].addr();
+    assert!(nodes[1].disconnect(hub).await);
+    eventually!(nodes[0].peers().await.is_empty());
+    for i in 0..2 {
+        nodes[0].broadcast(format!("missed {}", i)).await;
+    }
+    nodes[1].connect(hub).await.unwrap();
+    for i in 0..2 {
+        assert_eq!(
+            recv(&mut nodes[1]).await.unwrap().0,
+            format!("missed {}", i)
+        );
+    }
+    // and only those
+    nodes[0].broadcast("back".to_owned()).await;
+    assert_eq!(recv(&mut nodes[1]).await.unwrap().0, "back");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn anti_entropy_fills_in_what_replay_missed() {
+    let config = NodeConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        anti_entropy_interval: Duration::from_millis(200),
+        ..quiet()
+    };
+    // 0 on its own, 1 and 2 off together
+    let mut nodes = mesh(3, &[(1, 2)], config).await;
+    let sent = now_millis();
+    nodes[0].broadcast("missed".to_owned()).await;
+    // long enough ago that 1, which never lost its link to 2, doesn't ask
+    // for it to be replayed
+    eventually!(now_millis() > sent + 600);
+    let hub = nodes[0].addr();
+    nodes[1].connect(hub).await.unwrap();
+    let (msg, origin) = recv(&mut nodes[1])
+        .await
+        .expect("pulled in by anti-entropy");
+    assert_eq!((msg.as_str(), origin), ("missed", hub));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn every_codec_carries_causal_broadcasts() {
+    for codec in CodecKind::ALL {
+        let config = NodeConfig {
+            codecs: vec![codec],
+            causal: true,
+            ..quiet()
+        };
+        let mut nodes = mesh(3, &[(0, 1), (1, 2)], config).await;
+        nodes[0].broadcast(format!("over {:?}", codec)).await;
+        for node in &mut nodes[1..] {
+            let (msg, _) = recv(node).await.expect("delivered");
+            assert_eq!(msg, format!("over {:?}", codec));
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blobs_spread_from_neighbor_to_neighbor() {
+    let mut nodes = mesh(3, &[(0, 1), (1, 2)], quiet()).await;
+    let blob: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    // wait for 1 to hear about it
+    let before = packets_received(&mut nodes[1]).await;
+    let id = nodes[0].put_blob(blob.clone()).await.unwrap();
+    eventually!(packets_received(&mut nodes[1]).await > before);
+    let timeout = Duration::from_secs(5);
+    // 2 isn't next to anyone who has it yet
+    let early = nodes[2].fetch_blob(id, Duration::from_millis(300)).await;
+    assert_eq!(early.unwrap_err(), BlobError::Timeout);
+    assert_eq!(nodes[1].fetch_blob(id, timeout).await.unwrap(), blob);
+    assert_eq!(nodes[2].fetch_blob(id, timeout).await.unwrap(), blob);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn heartbeats_keep_idle_links_up() {
+    let config = NodeConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        ..quiet()
+    };
+    let mut nodes = mesh(2, &[(0, 1)], config).await;
+    // heartbeats for several times over what a silent link gets
+    for node in &mut nodes {
+        eventually!(node
+            .peers()
+            .await
+            .first()
+            .is_some_and(|p| p.messages_received >= 10));
+        assert_eq!(node.peers().await.len(), 1);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn silent_peers_are_dropped() {
+    let config = NodeConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        ..quiet()
+    };
+    let mut node: RunningNode<String> = Node::with_config(0, config.clone()).await.start();
+    // never started, so it shakes hands and then says nothing at all
+    let ghost: Node<String> = Node::with_config(0, config).await;
+    let mut stream = TcpStream::connect(node.addr()).await.unwrap();
+    handshake(&mut stream, &ghost.hello()).await.unwrap();
+    eventually!(node.peers().await.len() == 1);
+    let joined = Instant::now();
+    eventually!(node.peers().await.is_empty());
+    // three heartbeats' worth of silence
+    assert!(joined.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn every_fragment_counts_towards_the_rate_limit() {
+    let config = NodeConfig {
+        fragment_size: 64,
+        peer_rate_limit: Some(RateLimit { rate: 1, burst: 10 }),
+        ..quiet()
+    };
+    let mut nodes = mesh(2, &[(0, 1)], config).await;
+    nodes[0].broadcast("small".to_owned()).await;
+    assert_eq!(recv(&mut nodes[1]).await.unwrap().0, "small");
+    // one packet, but far more frames than the burst allows
+    nodes[0].broadcast("x".repeat(2000)).await;
+    eventually!(nodes[1].stats().await.unwrap().rate_limited > 0);
+    // with a fragment thrown away it can never be put back together, but
+    // the link stays up
+    assert_eq!(nodes[1].peers().await.len(), 1);
+    let got = tokio::time::timeout(Duration::ZERO, nodes[1].recv()).await;
+    assert!(got.is_err(), "got {:?}", got);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_keeps_to_its_timeout_with_a_peer_still_talking() {
+    use tokio::io::AsyncWriteExt;
+
+    let mut node: RunningNode<String> = Node::with_config(0, quiet()).await.start();
+    let ghost: Node<String> = Node::with_config(0, quiet()).await;
+    let mut stream = TcpStream::connect(node.addr()).await.unwrap();
+    handshake(&mut stream, &ghost.hello()).await.unwrap();
+    // heartbeats as plain bincode frames, faster than the node takes them
+    // once it's shutting down, and never reading what it sends back
+    let pkt: Packet<String> = Packet::new(
+        Operation::Link,
+        ghost.addr,
+        Payload::Control(ControlMsg::Heartbeat),
+    );
+    let mut frame = vec![0];
+    frame.extend(bincode::serialize(&pkt).unwrap());
+    tokio::spawn(async move {
+        while stream.write_u64(frame.len() as u64).await.is_ok()
+            && stream.write_all(&frame).await.is_ok()
+        {}
+    });
+    eventually!(packets_received(&mut node).await > 0);
+    let shutdown = node.shutdown(Duration::from_millis(200));
+    assert!(tokio::time::timeout(Duration::from_secs(3), shutdown)
+        .await
+        .is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_cuts_stalled_handshakes_short() {
+    use tokio::io::AsyncReadExt;
+
+    use crate::handshake::MAGIC;
+
+    let node: RunningNode<String> = Node::with_config(0, quiet()).await.start();
+    // connects, then never says hello
+    let mut silent = TcpStream::connect(node.addr()).await.unwrap();
+    // the node's half of the handshake, so it's waiting on ours
+    let mut magic = [0; 4];
+    silent.read_exact(&mut magic).await.unwrap();
+    assert_eq!(magic, MAGIC);
+    let shutdown = node.shutdown(Duration::from_millis(200));
+    assert!(tokio::time::timeout(Duration::from_secs(2), shutdown)
+        .await
+        .is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn both_ends_see_the_same_link() {
+    let mut nodes = mesh(2, &[], quiet()).await;
+    let (a, b) = (nodes[0].addr(), nodes[1].addr());
+    assert_eq!(nodes[0].connect(b).await.unwrap(), b);
+    nodes[0].broadcast("x".repeat(100_000)).await;
+    recv(&mut nodes[1]).await.unwrap();
+    nodes[1].broadcast("back".to_owned()).await;
+    recv(&mut nodes[0]).await.unwrap();
+    idle(&mut nodes).await;
+
+    let ours = nodes[0].peers().await;
+    let theirs = nodes[1].peers().await;
+    assert_eq!((ours.len(), theirs.len()), (1, 1));
+    let (ours, theirs) = (&ours[0], &theirs[0]);
+    assert_eq!((ours.addr, ours.node), (b, Some(b)));
+    assert_eq!(ours.direction, Direction::Outbound);
+    // it knows us by the port we dialed from
+    assert_ne!(theirs.addr, a);
+    assert_eq!(theirs.node, Some(a));
+    assert_eq!(theirs.direction, Direction::Inbound);
+    // counted the same way at both ends, fragments and all
+    assert!(ours.bytes_sent > 100_000);
+    assert_eq!(ours.bytes_sent, theirs.bytes_received);
+    assert_eq!(ours.bytes_received, theirs.bytes_sent);
+    assert_eq!(ours.messages_sent, theirs.messages_received);
+    assert_eq!(ours.messages_received, theirs.messages_sent);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn disconnecting_drops_the_link_at_both_ends() {
+    let mut nodes = mesh(2, &[(0, 1)], quiet()).await;
+    let b = nodes[1].addr();
+    assert!(nodes[0].disconnect(b).await);
+    assert!(nodes[0].peers().await.is_empty());
+    eventually!(nodes[1].peers().await.is_empty());
+    assert!(!nodes[0].disconnect(b).await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn banned_nodes_are_refused_until_unbanned() {
+    let mut nodes = mesh(2, &[(0, 1)], quiet()).await;
+    let (a, b) = (nodes[0].addr(), nodes[1].addr());
+    nodes[0].ban(b).await;
+    assert!(nodes[0].peers().await.is_empty());
+    eventually!(nodes[1].peers().await.is_empty());
+    assert!(matches!(
+        nodes[0].connect(b).await,
+        Err(HandshakeError::Banned)
+    ));
+    // it can still dial us, but we hang up once it says who it is
+    let _ = nodes[1].connect(a).await;
+    eventually!(nodes[1].peers().await.is_empty());
+    assert!(nodes[0].peers().await.is_empty());
+
+    nodes[0].unban(b).await;
+    assert_eq!(nodes[0].connect(b).await.unwrap(), b);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn banned_hosts_are_refused_whoever_they_say_they_are() {
+    use tokio::io::AsyncReadExt;
+
+    let mut nodes = mesh(3, &[(0, 1)], quiet()).await;
+    let (a, c) = (nodes[0].addr(), nodes[2].addr());
+    nodes[0].ban_ip(a.ip()).await;
+    assert!(nodes[0].peers().await.is_empty());
+    eventually!(nodes[1].peers().await.is_empty());
+    // a stranger gets through its half of the handshake, then hung up on
+    let stranger: Node<String> = Node::with_config(0, quiet()).await;
+    let mut stream = TcpStream::connect(a).await.unwrap();
+    handshake(&mut stream, &stranger.hello()).await.unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+    assert!(nodes[0].peers().await.is_empty());
+    assert!(matches!(
+        nodes[0].connect(c).await,
+        Err(HandshakeError::Banned)
+    ));
+
+    nodes[0].unban_ip(a.ip()).await;
+    assert_eq!(nodes[0].connect(c).await.unwrap(), c);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nodes_that_dial_each_other_keep_one_link() {
+    // a few times over, as which handshake finishes first varies
+    for _ in 0..5 {
+        let mut nodes = mesh(2, &[], quiet()).await;
+        let (a, b) = (nodes[0].addr(), nodes[1].addr());
+        let (first, second) = nodes.split_at_mut(1);
+        let (ab, ba) = tokio::join!(first[0].connect(b), second[0].connect(a));
+        assert_eq!((ab.unwrap(), ba.unwrap()), (b, a));
+        // one link, the same one seen from either end
+        eventually!({
+            let ours = nodes[0].peers().await;
+            let theirs = nodes[1].peers().await;
+            ours.len() == 1 && theirs.len() == 1 && ours[0].direction != theirs[0].direction
+        });
+        nodes[0].broadcast("once".to_owned()).await;
+        nodes[0].broadcast("twice".to_owned()).await;
+        assert_eq!(recv(&mut nodes[1]).await.unwrap().0, "once");
+        assert_eq!(recv(&mut nodes[1]).await.unwrap().0, "twice");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_restarted_node_replaces_its_old_link() {
+    let mut nodes = mesh(2, &[(0, 1)], quiet()).await;
+    let b = nodes[1].addr();
+    // the old b goes without a word, so a still has its link
+    nodes.pop().unwrap().terminate().await;
+    let mut back: RunningNode<String> = Node::with_config(b.port(), quiet()).await.start();
+    back.connect(nodes[0].addr()).await.unwrap();
+
+    eventually!({
+        let peers = nodes[0].peers().await;
+        peers.len() == 1 && peers[0].direction == Direction::Inbound
+    });
+    nodes[0].broadcast("hello again".to_owned()).await;
+    assert_eq!(recv(&mut back).await.unwrap().0, "hello again");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reliable_messages_ordered_delivery_gave_up_on_still_arrive() {
+    let config = NodeConfig {
+        ordered: true,
+        reorder_wait: Duration::from_millis(100),
+        reliable_retry: Duration::from_secs(1),
+        // nothing replayed on connecting, the retry is all there is
+        history_len: 0,
+        ..quiet()
+    };
+    let mut nodes = mesh(2, &[], config).await;
+    let b = nodes[1].addr();
+    // goes nowhere, b isn't linked up yet
+    let (tx, report) = oneshot::channel();
+    let timeout = Duration::from_secs(5);
+    nodes[0]
+        .send_cmd(MetaCommand::BroadcastReliable(
+            "first".to_owned(),
+            vec![b],
+            timeout,
+            tx,
+        ))
+        .await;
+    nodes[0].connect(b).await.unwrap();
+    nodes[0].broadcast("second".to_owned()).await;
+    // b waits on "first" for a while, then moves on without it
+    assert_eq!(recv(&mut nodes[1]).await.unwrap().0, "second");
+    // and the retry gets there late, but it's been promised
+    assert_eq!(recv(&mut nodes[1]).await.unwrap().0, "first");
+    let report = report.await.unwrap().unwrap();
+    assert!(report.confirmed.contains(&b));
+}
+
+// one thread, so everything below is queued up before the node gets to run
+#[tokio::test]
+async fn alarms_go_before_commands_already_waiting() {
+    let mut nodes = mesh(2, &[(0, 1)], quiet()).await;
+    for round in 0..4 {
+        let before = nodes[0].stats().await.unwrap().packets_sent;
+        let (tx, stats) = oneshot::channel();
+        nodes[0].send_cmd(MetaCommand::Stats(tx)).await;
+        for i in 0..10 {
+            nodes[0].broadcast(format!("chatter {}", i)).await;
+        }
+        let alarm = format!("alarm {}", round);
+        nodes[0]
+            .broadcast_with_priority(alarm.clone(), Priority::Critical)
+            .await;
+        // the alarm went out before the node got to anything else
+        assert_eq!(stats.await.unwrap().packets_sent, before + 1);
+        assert_eq!(recv(&mut nodes[1]).await.unwrap().0, alarm);
+        for _ in 0..10 {
+            recv(&mut nodes[1]).await.unwrap();
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn neighbors_cant_advertise_routes_under_another_name() {
+    use tokio::io::AsyncWriteExt;
+
+    let mut node: RunningNode<String> = Node::with_config(0, quiet()).await.start();
+    let ghost: Node<String> = Node::with_config(0, quiet()).await;
+    let mut stream = TcpStream::connect(node.addr()).await.unwrap();
+    handshake(&mut stream, &ghost.hello()).await.unwrap();
+    let victim = SocketAddr::from(([127, 0, 0, 1], 9));
+    let pkt: Packet<String> = Packet::new(
+        Operation::Link,
+        victim,
+        Payload::Control(ControlMsg::Routes(vec![])),
+    );
+    let mut frame = vec![0];
+    frame.extend(bincode::serialize(&pkt).unwrap());
+    stream.write_u64(frame.len() as u64).await.unwrap();
+    stream.write_all(&frame).await.unwrap();
+
+    // wait for the node to have taken it in
+    eventually!(packets_received(&mut node).await > 0);
+    let dests: Vec<_> = node.routes().await.iter().map(|r| r.dest).collect();
+    assert_eq!(dests, vec![ghost.addr]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_cut_off_for_flooding_are_dropped_at_once() {
+    use tokio::io::AsyncWriteExt;
+
+    // heartbeats are a minute apart, so only the cut-off can drop it
+    let config = NodeConfig {
+        peer_rate_limit: Some(RateLimit { rate: 1, burst: 5 }),
+        over_rate_limit: OverLimit::Disconnect,
+        ..quiet()
+    };
+    let mut node: RunningNode<String> = Node::with_config(0, config).await.start();
+    let ghost: Node<String> = Node::with_config(0, quiet()).await;
+    let mut stream = TcpStream::connect(node.addr()).await.unwrap();
+    handshake(&mut stream, &ghost.hello()).await.unwrap();
+    let frame = |msg| {
+        let pkt: Packet<String> = Packet::new(Operation::Link, ghost.addr, Payload::Control(msg));
+        let mut frame = vec![0];
+        frame.extend(bincode::serialize(&pkt).unwrap());
+        frame
+    };
+    let routes = frame(ControlMsg::Routes(vec![]));
+    stream.write_u64(routes.len() as u64).await.unwrap();
+    stream.write_all(&routes).await.unwrap();
+    eventually!(!node.routes().await.is_empty());
+
+    let heartbeat = frame(ControlMsg::Heartbeat);
+    for _ in 0..10 {
+        let _ = stream.write_u64(heartbeat.len() as u64).await;
+        let _ = stream.write_all(&heartbeat).await;
+    }
+    eventually!(node.peers().await.is_empty());
+    // poisoned, so the rest of the network hears it's gone
+    let routes = node.routes().await;
+    assert!(routes.iter().all(|r| r.hops == crate::routing::INFINITY));
+    assert_eq!(node.stats().await.unwrap().rate_limit_disconnects, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn calls_on_a_stopped_node_say_so() {
+    let mut node: RunningNode<String> = Node::with_config(0, quiet()).await.start();
+    let addr = node.addr();
+    node.send_cmd(MetaCommand::Die).await;
+    let asked = node.request(addr, "hi".to_owned(), Duration::from_secs(1));
+    assert_eq!(asked.await, Err(RpcError::NodeStopped(NodeStopped)));
+    let stored = node.dht_put(b"key", b"value".to_vec()).await;
+    assert_eq!(stored, Err(NodeStopped.into()));
+    assert_eq!(NodeStopped.to_string(), "node stopped");
+    assert_eq!(stored.unwrap_err().to_string(), "node stopped");
+}
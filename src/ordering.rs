@@ -0,0 +1,173 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// Where a broadcast falls among the ones its origin has made. Counts from 1
+/// within an epoch, the time the origin started, so the count starting over
+/// after a restart doesn't look like a pile of old messages.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Seq {
+    pub epoch: u64,
+    pub n: u64,
+}
+
+/// Where we are in one origin's stream of broadcasts.
+struct Stream<M> {
+    epoch: u64,
+    /// The sequence number we're waiting to deliver next.
+    next: u64,
+    /// Messages that got here before the ones in front of them.
+    held: BTreeMap<u64, (M, Instant)>,
+}
+
+/// Puts broadcasts back in the order their origin sent them. A gap is waited
+/// on for at most `max_wait`, after that the missing messages are given up on
+/// and anything that shows up for them later is dropped. When an origin
+/// restarts its new stream replaces the old one.
+pub struct ReorderBuffer<M> {
+    streams: HashMap<SocketAddr, Stream<M>>,
+    max_wait: Duration,
+    /// Messages thrown away because they arrived after we'd moved past them.
+    pub late: u64,
+}
+
+impl<M> Stream<M> {
+    fn new(epoch: u64) -> Self {
+        Self {
+            epoch,
+            next: 1,
+            held: Default::default(),
+        }
+    }
+}
+
+impl<M> ReorderBuffer<M> {
+    pub fn new(max_wait: Duration) -> Self {
+        Self {
+            streams: Default::default(),
+            max_wait,
+            late: 0,
+        }
+    }
+
+    /// Message `seq` from `origin` arrived. Returns whatever can be delivered
+    /// now, in order.
+    pub fn push(&mut self, origin: SocketAddr, seq: Seq, msg: M) -> Vec<M> {
+        let stream = self
+            .streams
+            .entry(origin)
+            .or_insert_with(|| Stream::new(seq.epoch));
+        let mut ready = Vec::new();
+        if seq.epoch > stream.epoch {
+            // the origin restarted, nothing more is coming for the old stream
+            let old = std::mem::replace(stream, Stream::new(seq.epoch));
+            ready.extend(old.held.into_values().map(|(msg, _)| msg));
+        }
+        if seq.epoch < stream.epoch || seq.n < stream.next {
+            self.late += 1;
+            return ready;
+        }
+        stream.held.entry(seq.n).or_insert((msg, Instant::now()));
+        ready.extend(Self::drain(stream));
+        ready
+    }
+
+    /// Whether message `seq` from `origin` would be thrown away as late.
+    pub fn is_late(&self, origin: &SocketAddr, seq: &Seq) -> bool {
+        self.streams.get(origin).is_some_and(|stream| {
+            seq.epoch < stream.epoch || (seq.epoch == stream.epoch && seq.n < stream.next)
+        })
+    }
+
+    /// Skip over gaps that have been waited on for too long, returning the
+    /// messages that were stuck behind them.
+    pub fn expire(&mut self) -> Vec<(M, SocketAddr)> {
+        let max_wait = self.max_wait;
+        let mut out = Vec::new();
+        for (origin, stream) in self.streams.iter_mut() {
+            // anything held has been stuck behind the first gap since it
+            // arrived, so the longest wait decides
+            while let Some(oldest) = stream.held.values().map(|(_, at)| *at).min() {
+                if oldest.elapsed() < max_wait {
+                    break;
+                }
+                stream.next = *stream.held.keys().next().expect("not empty");
+                out.extend(Self::drain(stream).into_iter().map(|m| (m, *origin)));
+            }
+        }
+        out
+    }
+
+    fn drain(stream: &mut Stream<M>) -> Vec<M> {
+        let mut ready = Vec::new();
+        while let Some((msg, _)) = stream.held.remove(&stream.next) {
+            ready.push(msg);
+            stream.next += 1;
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    fn seq(epoch: u64, n: u64) -> Seq {
+        Seq { epoch, n }
+    }
+
+    #[test]
+    fn gaps_hold_back_what_comes_after_them() {
+        let mut buf = ReorderBuffer::new(Duration::from_secs(60));
+        assert_eq!(buf.push(addr(1), seq(5, 1), 1), vec![1]);
+        assert!(buf.push(addr(1), seq(5, 3), 3).is_empty());
+        assert!(buf.push(addr(1), seq(5, 4), 4).is_empty());
+        // other origins don't wait on it
+        assert_eq!(buf.push(addr(2), seq(5, 1), 10), vec![10]);
+        assert_eq!(buf.push(addr(1), seq(5, 2), 2), vec![2, 3, 4]);
+        // duplicates of what's been delivered are late
+        assert!(buf.push(addr(1), seq(5, 2), 2).is_empty());
+        assert_eq!(buf.late, 1);
+    }
+
+    #[tokio::test]
+    async fn gaps_are_given_up_on_after_a_while() {
+        tokio::time::pause();
+        let mut buf = ReorderBuffer::new(Duration::from_millis(20));
+        buf.push(addr(1), seq(5, 1), 1);
+        buf.push(addr(1), seq(5, 3), 3);
+        assert!(buf.expire().is_empty());
+        tokio::time::advance(Duration::from_millis(30)).await;
+        assert_eq!(buf.expire(), vec![(3, addr(1))]);
+        // 2 finally shows up, but we've moved past it
+        assert!(buf.is_late(&addr(1), &seq(5, 2)));
+        assert!(!buf.is_late(&addr(1), &seq(5, 4)));
+        assert!(!buf.is_late(&addr(2), &seq(5, 1)));
+        assert!(buf.push(addr(1), seq(5, 2), 2).is_empty());
+        assert_eq!(buf.late, 1);
+        assert_eq!(buf.push(addr(1), seq(5, 4), 4), vec![4]);
+    }
+
+    #[test]
+    fn a_restarted_origin_starts_a_new_stream() {
+        let mut buf = ReorderBuffer::new(Duration::from_secs(60));
+        for n in 1..=10 {
+            buf.push(addr(1), seq(5, n), n);
+        }
+        buf.push(addr(1), seq(5, 12), 12);
+        // back up, counting from 1 again. what was held can't be filled in
+        // anymore, so it goes out first
+        assert_eq!(buf.push(addr(1), seq(9, 1), 1), vec![12, 1]);
+        assert_eq!(buf.push(addr(1), seq(9, 2), 2), vec![2]);
+        // stragglers from before the restart are late
+        assert!(buf.is_late(&addr(1), &seq(5, 11)));
+        assert!(buf.push(addr(1), seq(5, 11), 11).is_empty());
+        assert_eq!(buf.late, 1);
+    }
+}
@@ -1,66 +1,884 @@
-use std::marker::PhantomData;
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::Duration,
+};
 
-use crate::proto::{Packet, SanePayload};
+use crate::{
+    codec::{Codec, CodecKind},
+    compress::Compression,
+    handshake::Feature,
+    proto::{Packet, Priority, SanePayload},
+    ratelimit::{InboundLimits, TokenBucket, Verdict},
+    sched::lanes,
+};
 
+use futures::{future::poll_fn, FutureExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    io::{self, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     net::TcpStream,
-    sync::mpsc,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
 };
 
+/// Frames bigger than this are refused rather than allocated for.
+const MAX_FRAME_LEN: u64 = 16 * 1024 * 1024;
+/// Same for whole messages put back together from fragments.
+const MAX_MESSAGE_LEN: u64 = 64 * 1024 * 1024;
+/// How many encoded packets can be waiting for the writer task.
+const WRITE_QUEUE_LEN: usize = 64;
+
+/// Frame flags, the first byte after the length.
+const FRAME_RAW: u8 = 0;
+const FRAME_COMPRESSED: u8 = 1;
+/// Part of a bigger frame, see `fragment`.
+const FRAME_FRAGMENT: u8 = 2;
+/// Fragment id, total length and offset.
+const FRAGMENT_HEADER_LEN: usize = 24;
+/// How many fragmented frames can be part way through a link at once. The
+/// sender sticks to it, a receiver drops links that go over.
+const MAX_PARTIAL_FRAMES: usize = 8;
+
+/// How packets are put on a link, as agreed on in the handshake.
+#[derive(Clone, Copy, Debug)]
+pub struct Framing {
+    pub codec: CodecKind,
+    /// None if the two ends have no compression in common.
+    pub compression: Option<Compression>,
+    /// Frames smaller than this aren't worth compressing.
+    pub compress_threshold: usize,
+    /// Frames bigger than this are sent in pieces of this size, taking turns
+    /// with other traffic instead of holding up the link.
+    pub fragment_size: usize,
+    /// How long a half reassembled message is kept around.
+    pub reassembly_timeout: Duration,
+}
+
+impl Framing {
+    /// The body of a frame: a flag byte saying whether the rest is
+    /// compressed, then the encoded packet. On the wire it's preceded by its
+    /// length as a u64.
+    fn encode<M: SanePayload>(&self, packet: &Packet<M>) -> io::Result<Vec<u8>> {
+        let buf = self.codec.encode(packet)?;
+        if let Some(compression) = self.compression {
+            if buf.len() >= self.compress_threshold {
+                let compressed = compression.compress(&buf);
+                // incompressible data gets bigger, send it as is then
+                if compressed.len() < buf.len() {
+                    return Ok(body(FRAME_COMPRESSED, &compressed));
+                }
+            }
+        }
+        Ok(body(FRAME_RAW, &buf))
+    }
+
+    fn decode<M: SanePayload>(&self, body: &[u8]) -> io::Result<Packet<M>> {
+        let (flag, rest) = body
+            .split_first()
+            .ok_or_else(|| invalid("empty frame".into()))?;
+        match (*flag, self.compression) {
+            (FRAME_RAW, _) => Ok(self.codec.decode(rest)?),
+            (FRAME_COMPRESSED, Some(compression)) => {
+                let buf = compression.decompress(rest, MAX_MESSAGE_LEN as usize)?;
+                Ok(self.codec.decode(&buf)?)
+            }
+            (flag, _) => Err(invalid(format!("unexpected frame flag {}", flag))),
+        }
+    }
+}
+
+fn body(flag: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + data.len());
+    out.push(flag);
+    out.extend_from_slice(data);
+    out
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Lengths come off the wire before what they measure, refuse the ones we
+/// have no use for before allocating anything.
+fn check_len(len: u64, max: u64) -> io::Result<()> {
+    if len == 0 {
+        return Err(invalid("empty frame".into()));
+    }
+    if len > max {
+        return Err(invalid(format!("{} byte frame is too big", len)));
+    }
+    Ok(())
+}
+
+async fn write_frame(
+    stream: &mut WriteHalf<TcpStream>,
+    body: &[u8],
+    sent: &Traffic,
+) -> io::Result<()> {
+    stream.write_u64(body.len() as u64).await?;
+    stream.write_all(body).await?;
+    sent.frame(body.len() as u64);
+    Ok(())
+}
+
+/// A frame body too big to send in one go, and how much of it is out.
+struct Outgoing {
+    id: u64,
+    body: Vec<u8>,
+    sent: usize,
+}
+
+impl Outgoing {
+    /// The next piece as a fragment frame body: the flag, the id, the total
+    /// length of the frame body being split up, the offset of this piece in
+    /// it, then the piece.
+    fn fragment(&mut self, size: usize) -> Vec<u8> {
+        let end = (self.sent + size).min(self.body.len());
+        let mut out = Vec::with_capacity(1 + FRAGMENT_HEADER_LEN + end - self.sent);
+        out.push(FRAME_FRAGMENT);
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&(self.body.len() as u64).to_be_bytes());
+        out.extend_from_slice(&(self.sent as u64).to_be_bytes());
+        out.extend_from_slice(&self.body[self.sent..end]);
+        self.sent = end;
+        out
+    }
+}
+
+/// The next frame body from the most urgent queue that has one, with the
+/// index of that queue. None once they're all closed.
+async fn next_frame(queues: &mut [mpsc::Receiver<Vec<u8>>]) -> Option<(usize, Vec<u8>)> {
+    poll_fn(|cx| {
+        let mut closed = 0;
+        for (lane, queue) in queues.iter_mut().enumerate() {
+            match queue.poll_recv(cx) {
+                Poll::Ready(Some(body)) => return Poll::Ready(Some((lane, body))),
+                Poll::Ready(None) => closed += 1,
+                Poll::Pending => {}
+            }
+        }
+        if closed == queues.len() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Sends everything queued until the queues are closed, a queue per
+/// priority. Small frames go out as soon as they're queued, most urgent
+/// first, big ones a fragment at a time, taking turns with others of the
+/// same priority and letting any small frames that come in meanwhile go
+/// first. At most `MAX_PARTIAL_FRAMES` big ones are in progress at once.
+async fn send_queued(
+    stream: &mut WriteHalf<TcpStream>,
+    mut queues: Vec<mpsc::Receiver<Vec<u8>>>,
+    fragment_size: usize,
+    sent: &Traffic,
+) -> io::Result<()> {
+    let mut big: Vec<VecDeque<Outgoing>> = queues.iter().map(|_| VecDeque::new()).collect();
+    let mut next_id = 0;
+    loop {
+        // wait for something to do if there's nothing in progress
+        let mut next = if big.iter().all(VecDeque::is_empty) {
+            match next_frame(&mut queues).await {
+                Some(next) => Some(next),
+                None => return Ok(()),
+            }
+        } else {
+            next_frame(&mut queues).now_or_never().flatten()
+        };
+        while let Some((lane, body)) = next {
+            if body.len() > fragment_size {
+                next_id += 1;
+                big[lane].push_back(Outgoing {
+                    id: next_id,
+                    body,
+                    sent: 0,
+                });
+            } else {
+                write_frame(stream, &body, sent).await?;
+            }
+            next = next_frame(&mut queues).now_or_never().flatten();
+        }
+        // the receiver only puts so many together at once, big frames beyond
+        // that wait for one to finish even if they're more urgent
+        let started = big.iter().flatten().filter(|o| o.sent > 0).count();
+        let next = big.iter().enumerate().find_map(|(lane, frames)| {
+            let pos = frames
+                .iter()
+                .position(|o| o.sent > 0 || started < MAX_PARTIAL_FRAMES)?;
+            Some((lane, pos))
+        });
+        if let Some((lane, pos)) = next {
+            let mut out = big[lane].remove(pos).expect("just found it");
+            write_frame(stream, &out.fragment(fragment_size), sent).await?;
+            if out.sent < out.body.len() {
+                big[lane].push_back(out);
+            }
+        }
+        stream.flush().await?;
+    }
+}
+
+/// Owns the write half of a link. Once the queues are closed and everything
+/// in them is out, sends `last_words` if there are any and hangs up. Stops
+/// straight away if the reader does, so the connection is closed as soon as
+/// either side is done with it.
+async fn write_loop(
+    mut stream: WriteHalf<TcpStream>,
+    queues: Vec<mpsc::Receiver<Vec<u8>>>,
+    fragment_size: usize,
+    sent: Arc<Traffic>,
+    mut reader_gone: oneshot::Receiver<()>,
+    mut last_words: oneshot::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    tokio::select! {
+        done = send_queued(&mut stream, queues, fragment_size, &sent) => done?,
+        _ = &mut reader_gone => return Ok(()),
+    }
+    let hang_up = async {
+        if let Ok(body) = last_words.try_recv() {
+            write_frame(&mut stream, &body, &sent).await?;
+        }
+        stream.shutdown().await
+    };
+    tokio::select! {
+        done = hang_up => done,
+        _ = reader_gone => Ok(()),
+    }
+}
+
+/// Who opened a link.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// We dialed them.
+    Outbound,
+    /// They dialed us.
+    Inbound,
+}
+
+/// A snapshot of a link and what's been over it, see `RunningNode::peers`.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    /// The address of the link: the one we dialed, or the one an accepted
+    /// connection came from.
+    pub addr: SocketAddr,
+    /// The node on the other end.
+    pub node: Option<SocketAddr>,
+    pub direction: Direction,
+    pub connected_since: Instant,
+    /// Traffic of every kind, not just application messages. Bytes are
+    /// everything that went over the wire, frame lengths and fragment
+    /// headers included. What came in includes anything that was then
+    /// dropped for going over a rate limit.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub rtt: Option<Duration>,
+}
+
+/// What has gone one way over a link. Bytes are counted by the task doing
+/// the reading or writing, as they go over the wire.
+#[derive(Default)]
+struct Traffic {
+    bytes: AtomicU64,
+    packets: AtomicU64,
+}
+
+impl Traffic {
+    /// A frame with a body of `len` bytes, and the length in front of it.
+    fn frame(&self, len: u64) {
+        self.bytes.fetch_add(8 + len, Ordering::Relaxed);
+    }
+}
+
 pub struct Peer<M> {
-    stream: WriteHalf<TcpStream>,
+    addr: SocketAddr,
+    direction: Direction,
+    connected_since: Instant,
+    /// Encoded frame bodies for the writer task, a queue per priority.
+    queues: Vec<mpsc::Sender<Vec<u8>>>,
+    framing: Framing,
+    /// The address of the node on the other end, from its hello. For
+    /// accepted connections this differs from the address we know it by.
+    pub node: Option<SocketAddr>,
+    /// When the node on the other end started, from its hello.
+    pub epoch: u64,
+    /// What the other end said it supports in its hello.
+    pub features: Vec<Feature>,
+    /// The node that dialed this link and the socket it dialed from, which
+    /// both ends agree on, unlike the addresses they know each other by.
+    pub dialer: Option<(SocketAddr, SocketAddr)>,
+    /// Last measured round trip time on this link.
+    pub rtt: Option<Duration>,
+    /// When anything last came in on this link.
+    pub last_heard: Instant,
+    /// When we last queued anything for it.
+    pub last_sent: Instant,
+    sent: Arc<Traffic>,
+    received: Arc<Traffic>,
+    /// Dropped along with the peer, which stops the task reading off the
+    /// link, so one we've given up on doesn't keep feeding us packets.
+    stop_reading: oneshot::Sender<()>,
+    /// A last frame for the writer to send before it hangs up, see `close`.
+    last_words: oneshot::Sender<Vec<u8>>,
+    reader: JoinHandle<io::Result<()>>,
+    writer: JoinHandle<io::Result<()>>,
     phantom: PhantomData<M>,
 }
 
 impl<M: SanePayload> Peer<M> {
-    pub fn new(stream: TcpStream, tx: mpsc::Sender<Packet<M>>) -> Self {
+    /// Packets read off the stream are sent into the queue in `tx` for their
+    /// priority (these should be this link's alone), unless they're over
+    /// `limits`. A link cut off for going over them is sent to `cut_off`.
+    /// Both directions use `framing`.
+    pub fn new(
+        stream: TcpStream,
+        addr: SocketAddr,
+        direction: Direction,
+        framing: Framing,
+        limits: Arc<InboundLimits>,
+        tx: Vec<mpsc::Sender<Packet<M>>>,
+        cut_off: mpsc::Sender<SocketAddr>,
+    ) -> Self {
         let (read, write) = tokio::io::split(stream);
-        let rcvr = Receiver::new(read);
-        tokio::spawn(rcvr.recv_into_chan(tx));
+        let received = Arc::new(Traffic::default());
+        let rcvr = Receiver::new(read, addr, framing, limits, received.clone(), cut_off);
+        let (stop_reading, stop) = oneshot::channel();
+        let (reader_done, reader_gone) = oneshot::channel();
+        let reader = tokio::spawn(rcvr.recv_into_chan(tx, stop, reader_done));
+        let (queues, queued) = lanes(WRITE_QUEUE_LEN);
+        let (last_words, said) = oneshot::channel();
+        let sent = Arc::new(Traffic::default());
+        let writer = tokio::spawn(write_loop(
+            write,
+            queued,
+            framing.fragment_size.max(1),
+            sent.clone(),
+            reader_gone,
+            said,
+        ));
         Self {
-            stream: write,
+            addr,
+            direction,
+            connected_since: Instant::now(),
+            queues,
+            framing,
+            node: None,
+            epoch: 0,
+            features: Vec::new(),
+            dialer: None,
+            rtt: None,
+            last_heard: Instant::now(),
+            last_sent: Instant::now(),
+            sent,
+            received,
+            stop_reading,
+            last_words,
+            reader,
+            writer,
             phantom: PhantomData,
         }
     }
 
+    /// Queue a packet for the writer task. This only fails once the link
+    /// has, so a write error shows up on the send after it.
     pub async fn send_packet(&mut self, packet: &Packet<M>) -> tokio::io::Result<()> {
-        let buf = bincode::serialize(&packet).expect("TODO: handle serialization failures");
-        self.stream.write_u64(buf.len() as u64).await?;
-        self.stream.write_all(&buf[..]).await?;
-        self.stream.flush().await?;
-        Ok(())
+        let body = self.framing.encode(packet)?;
+        self.last_sent = Instant::now();
+        self.sent.packets.fetch_add(1, Ordering::Relaxed);
+        self.queues[packet.priority.lane()]
+            .send(body)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "link is down"))
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            addr: self.addr,
+            node: self.node,
+            direction: self.direction,
+            connected_since: self.connected_since,
+            bytes_sent: self.sent.bytes.load(Ordering::Relaxed),
+            bytes_received: self.received.bytes.load(Ordering::Relaxed),
+            messages_sent: self.sent.packets.load(Ordering::Relaxed),
+            messages_received: self.received.packets.load(Ordering::Relaxed),
+            rtt: self.rtt,
+        }
+    }
+
+    /// Hang up nicely: send everything that's queued, then `goodbye`, then
+    /// close the connection once the other end has. Gives up on whatever
+    /// isn't done by `timeout`. Returns once both the reader and writer tasks
+    /// are done.
+    pub async fn close(self, goodbye: &Packet<M>, timeout: Duration) {
+        let Peer {
+            queues,
+            framing,
+            stop_reading,
+            last_words,
+            mut reader,
+            mut writer,
+            ..
+        } = self;
+        if let Ok(body) = framing.encode(goodbye) {
+            let _ = last_words.send(body);
+        }
+        drop(queues);
+        let deadline = tokio::time::Instant::now() + timeout;
+        let written = tokio::time::timeout_at(deadline, &mut writer).await.is_ok();
+        // wait for the other end to hang up in turn, as closing with packets
+        // from it still unread resets the connection, and that can throw away
+        // what we just sent before it's been read
+        let read = written && tokio::time::timeout_at(deadline, &mut reader).await.is_ok();
+        // this stops the writer too, if it's still going
+        drop(stop_reading);
+        if !read {
+            let _ = reader.await;
+        }
+        if !written {
+            let _ = writer.await;
+        }
+    }
+}
+
+/// Fragmented frames we're still putting together, by id.
+struct Reassembly {
+    partial: HashMap<u64, (Vec<u8>, Instant)>,
+    /// How long a half reassembled frame is kept around.
+    timeout: Duration,
+}
+
+impl Reassembly {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            partial: Default::default(),
+            timeout,
+        }
+    }
+
+    /// Add a fragment to the frame it belongs to, returning the whole frame
+    /// body once it's all here. Fragments of a frame arrive in order, as they
+    /// all come down the same stream.
+    fn add(&mut self, fragment: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if fragment.len() < FRAGMENT_HEADER_LEN {
+            return Err(invalid("short fragment".into()));
+        }
+        let field = |i: usize| {
+            let mut b = [0; 8];
+            b.copy_from_slice(&fragment[i * 8..i * 8 + 8]);
+            u64::from_be_bytes(b)
+        };
+        let (id, total, offset) = (field(0), field(1), field(2));
+        let data = &fragment[FRAGMENT_HEADER_LEN..];
+        check_len(total, MAX_MESSAGE_LEN)?;
+
+        let timeout = self.timeout;
+        self.partial
+            .retain(|_, (_, started)| started.elapsed() < timeout);
+        if !self.partial.contains_key(&id) && self.partial.len() >= MAX_PARTIAL_FRAMES {
+            return Err(invalid("too many fragmented frames at once".into()));
+        }
+        let (buf, _) = self
+            .partial
+            .entry(id)
+            .or_insert_with(|| (Vec::new(), Instant::now()));
+        if offset != buf.len() as u64 || offset + data.len() as u64 > total {
+            // a piece went missing (we timed it out), or it's nonsense
+            self.partial.remove(&id);
+            return Ok(None);
+        }
+        buf.extend_from_slice(data);
+        if (buf.len() as u64) < total {
+            return Ok(None);
+        }
+        Ok(self.partial.remove(&id).map(|(buf, _)| buf))
     }
 }
 
 struct Receiver<M> {
     stream: ReadHalf<TcpStream>,
+    addr: SocketAddr,
+    framing: Framing,
+    reassembly: Reassembly,
+    limits: Arc<InboundLimits>,
+    /// This link's share of `limits`.
+    bucket: Option<TokenBucket>,
+    /// Critical packets past this are demoted to normal.
+    critical: TokenBucket,
+    traffic: Arc<Traffic>,
+    /// Where to say we hung up for going over the rate limit.
+    cut_off: mpsc::Sender<SocketAddr>,
     phantom: PhantomData<M>,
 }
 
 impl<M: SanePayload> Receiver<M> {
-    fn new(stream: ReadHalf<TcpStream>) -> Self {
+    fn new(
+        stream: ReadHalf<TcpStream>,
+        addr: SocketAddr,
+        framing: Framing,
+        limits: Arc<InboundLimits>,
+        traffic: Arc<Traffic>,
+        cut_off: mpsc::Sender<SocketAddr>,
+    ) -> Self {
         Self {
+            traffic,
+            cut_off,
             stream,
+            addr,
+            framing,
+            reassembly: Reassembly::new(framing.reassembly_timeout),
+            bucket: limits.peer_bucket(),
+            critical: limits.critical_bucket(),
+            limits,
             phantom: PhantomData,
         }
     }
 
+    /// Garbage on the stream is an error like any other, it just ends the
+    /// connection. Rate limits count frames rather than packets, so a packet
+    /// cut into fragments pays for every one of them.
     async fn recv_packet(&mut self) -> tokio::io::Result<Packet<M>> {
-        let msg_len = self.stream.read_u64().await?;
-        let mut buf = vec![0u8; msg_len as usize];
-        self.stream.read_exact(&mut buf[..]).await?;
-        let pkt = bincode::deserialize(&buf[..]).expect("TODO: handle invalid packet data");
-        Ok(pkt)
+        loop {
+            let len = self.stream.read_u64().await?;
+            check_len(len, MAX_FRAME_LEN)?;
+            let mut buf = vec![0u8; len as usize];
+            self.stream.read_exact(&mut buf[..]).await?;
+            self.traffic.frame(len);
+            match self.limits.admit(&mut self.bucket) {
+                Verdict::Admit => {}
+                Verdict::Drop => continue,
+                Verdict::Disconnect => {
+                    // so the node drops it now, not when a send fails
+                    let _ = self.cut_off.send(self.addr).await;
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "over its rate limit",
+                    ));
+                }
+            }
+            if buf[0] != FRAME_FRAGMENT {
+                return self.framing.decode(&buf);
+            }
+            if let Some(body) = self.reassembly.add(&buf[1..])? {
+                return self.framing.decode(&body);
+            }
+        }
     }
 
-    async fn recv_into_chan(mut self, tx: mpsc::Sender<Packet<M>>) -> tokio::io::Result<()> {
+    async fn recv_into_chan(
+        mut self,
+        tx: Vec<mpsc::Sender<Packet<M>>>,
+        mut stop: oneshot::Receiver<()>,
+        // dropped on the way out, for the writer to notice
+        _done: oneshot::Sender<()>,
+    ) -> tokio::io::Result<()> {
         loop {
-            let pkt = self.recv_packet().await?;
-            if tx.send(pkt).await.is_err() {
+            let pkt = tokio::select! {
+                pkt = self.recv_packet() => pkt,
+                _ = &mut stop => break,
+            };
+            let mut pkt = match pkt {
+                Ok(pkt) => pkt,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        println!("dropping link {}: {}", self.addr, e);
+                    }
+                    return Err(e);
+                }
+            };
+            self.traffic.packets.fetch_add(1, Ordering::Relaxed);
+            if pkt.priority == Priority::Critical && !self.critical.take() {
+                pkt.priority = Priority::Normal;
+            }
+            // the node might not be taking any more, if it's shutting down
+            let sent = tokio::select! {
+                sent = tx[pkt.priority.lane()].send(pkt) => sent,
+                _ = &mut stop => break,
+            };
+            if sent.is_err() {
                 break;
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proto::{Operation, Payload},
+        ratelimit::{OverLimit, RateLimit},
+        testing::addr,
+    };
+
+    fn framing(compression: Option<Compression>) -> Framing {
+        Framing {
+            codec: CodecKind::Bincode,
+            compression,
+            compress_threshold: 256,
+            fragment_size: 1024,
+            reassembly_timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn packet<T>(msg: T) -> Packet<T> {
+        Packet::new(Operation::Link, addr(1), Payload::Message(msg))
+    }
+
+    /// Both ends of a fresh connection on localhost.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dialing = TcpStream::connect(listener.local_addr().unwrap());
+        let (dialed, accepted) = tokio::join!(dialing, listener.accept());
+        (dialed.unwrap(), accepted.unwrap().0)
+    }
+
+    #[test]
+    fn only_big_compressible_frames_are_compressed() {
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let framing = framing(Some(compression));
+            let small = packet(b"hi".to_vec());
+            let big = packet(b"hi ".repeat(500));
+            let noise = packet((0..1500).map(|_| rand::random()).collect());
+            for (pkt, flag) in [
+                (small, FRAME_RAW),
+                (big, FRAME_COMPRESSED),
+                (noise, FRAME_RAW),
+            ] {
+                let body = framing.encode(&pkt).unwrap();
+                assert_eq!(body[0], flag);
+                assert_eq!(framing.decode::<Vec<u8>>(&body).unwrap(), pkt);
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_frames_need_compression_agreed_on() {
+        let body = framing(Some(Compression::Lz4))
+            .encode(&packet("hi ".repeat(500)))
+            .unwrap();
+        let err = framing(None).decode::<String>(&body).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected frame flag 1");
+    }
+
+    #[test]
+    fn empty_frames_are_not_too_big() {
+        assert_eq!(check_len(0, 10).unwrap_err().to_string(), "empty frame");
+        assert_eq!(
+            check_len(11, 10).unwrap_err().to_string(),
+            "11 byte frame is too big"
+        );
+        assert!(check_len(1, 10).is_ok());
+        assert!(check_len(10, 10).is_ok());
+        let err = framing(None).decode::<String>(&[]).unwrap_err();
+        assert_eq!(err.to_string(), "empty frame");
+    }
+
+    /// `body` cut into fragments of `size`, without the flag byte.
+    fn fragments(id: u64, body: &[u8], size: usize) -> Vec<Vec<u8>> {
+        let mut out = Outgoing {
+            id,
+            body: body.to_vec(),
+            sent: 0,
+        };
+        let mut pieces = Vec::new();
+        while out.sent < out.body.len() {
+            pieces.push(out.fragment(size)[1..].to_vec());
+        }
+        pieces
+    }
+
+    #[test]
+    fn interleaved_frames_are_put_back_together() {
+        let mut reassembly = Reassembly::new(Duration::from_secs(60));
+        let (a, b) = (vec![1; 100], vec![2; 70]);
+        let (fa, fb) = (fragments(1, &a, 30), fragments(2, &b, 30));
+        assert_eq!((fa.len(), fb.len()), (4, 3));
+        let mut done = Vec::new();
+        for i in 0..4 {
+            for pieces in [&fa, &fb] {
+                if let Some(piece) = pieces.get(i) {
+                    done.extend(reassembly.add(piece).unwrap());
+                }
+            }
+        }
+        assert_eq!(done, vec![b, a]);
+        assert!(reassembly.partial.is_empty());
+    }
+
+    #[test]
+    fn out_of_order_or_repeated_pieces_drop_the_frame() {
+        let body = vec![7; 100];
+        let pieces = fragments(1, &body, 30);
+        let mut reassembly = Reassembly::new(Duration::from_secs(60));
+        reassembly.add(&pieces[0]).unwrap();
+        assert_eq!(reassembly.add(&pieces[2]).unwrap(), None);
+        assert_eq!(reassembly.add(&pieces[3]).unwrap(), None);
+        assert!(reassembly.partial.is_empty());
+
+        let mut reassembly = Reassembly::new(Duration::from_secs(60));
+        reassembly.add(&pieces[0]).unwrap();
+        reassembly.add(&pieces[1]).unwrap();
+        assert_eq!(reassembly.add(&pieces[1]).unwrap(), None);
+        assert!(pieces[2..]
+            .iter()
+            .all(|p| reassembly.add(p).unwrap().is_none()));
+
+        // the same id can be used again after
+        let again = fragments(1, &body, 50);
+        assert_eq!(reassembly.add(&again[0]).unwrap(), None);
+        assert_eq!(reassembly.add(&again[1]).unwrap(), Some(body));
+    }
+
+    #[tokio::test]
+    async fn stalled_frames_time_out() {
+        tokio::time::pause();
+        let pieces = fragments(1, &[7; 100], 30);
+        let mut reassembly = Reassembly::new(Duration::from_millis(10));
+        reassembly.add(&pieces[0]).unwrap();
+        reassembly.add(&pieces[1]).unwrap();
+        tokio::time::advance(Duration::from_millis(20)).await;
+        for piece in &pieces[2..] {
+            assert_eq!(reassembly.add(piece).unwrap(), None);
+        }
+        assert!(reassembly.partial.is_empty());
+    }
+
+    #[test]
+    fn too_many_frames_at_once_is_an_error() {
+        let mut reassembly = Reassembly::new(Duration::from_secs(60));
+        for id in 0..MAX_PARTIAL_FRAMES as u64 {
+            reassembly.add(&fragments(id, &[0; 10], 5)[0]).unwrap();
+        }
+        let err = reassembly.add(&fragments(99, &[0; 10], 5)[0]).unwrap_err();
+        assert_eq!(err.to_string(), "too many fragmented frames at once");
+        // the ones in progress can still finish
+        assert!(reassembly
+            .add(&fragments(0, &[0; 10], 5)[1])
+            .unwrap()
+            .is_some());
+        assert!(reassembly.add(&fragments(99, &[0; 10], 5)[0]).is_ok());
+        assert!(reassembly.add(&[0; 3]).is_err());
+    }
+
+    #[tokio::test]
+    async fn senders_stay_under_the_partial_frame_limit() {
+        let (dialed, accepted) = tcp_pair().await;
+        let (_, write) = io::split(dialed);
+        let (mut read, _) = io::split(accepted);
+
+        let (tx, queues) = lanes(64);
+        let bodies: Vec<_> = (0..3 * MAX_PARTIAL_FRAMES as u8)
+            .map(|i| vec![i; 1000])
+            .collect();
+        let sender = tokio::spawn(async move {
+            let mut write = write;
+            send_queued(&mut write, queues, 100, &Traffic::default()).await
+        });
+        for (i, body) in bodies.iter().enumerate() {
+            tx[i % tx.len()].send(body.clone()).await.unwrap();
+        }
+        drop(tx);
+
+        let mut reassembly = Reassembly::new(Duration::from_secs(60));
+        let mut received = Vec::new();
+        while received.len() < bodies.len() {
+            let len = read.read_u64().await.unwrap();
+            let mut buf = vec![0; len as usize];
+            read.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[0], FRAME_FRAGMENT);
+            received.extend(reassembly.add(&buf[1..]).unwrap());
+        }
+        sender.await.unwrap().unwrap();
+        received.sort();
+        assert_eq!(received, bodies);
+    }
+
+    #[tokio::test]
+    async fn the_most_urgent_frame_goes_first() {
+        let (tx, mut queues) = lanes(8);
+        for lane in (0..tx.len()).rev() {
+            tx[lane].send(vec![lane as u8]).await.unwrap();
+        }
+        for lane in 0..tx.len() {
+            assert_eq!(
+                next_frame(&mut queues).await,
+                Some((lane, vec![lane as u8]))
+            );
+        }
+        assert!(next_frame(&mut queues).now_or_never().is_none());
+        drop(tx);
+        assert_eq!(next_frame(&mut queues).await, None);
+    }
+
+    #[tokio::test]
+    async fn critical_packets_past_the_limit_are_demoted() {
+        let (dialed, accepted) = tcp_pair().await;
+        let (_, mut write) = io::split(dialed);
+        let (read, _) = io::split(accepted);
+        let limits = Arc::new(InboundLimits::new(
+            None,
+            None,
+            OverLimit::Drop,
+            RateLimit { rate: 1, burst: 2 },
+        ));
+        let (cut_off, _) = mpsc::channel(1);
+        let rcvr: Receiver<String> = Receiver::new(
+            read,
+            addr(1),
+            framing(None),
+            limits,
+            Default::default(),
+            cut_off,
+        );
+        let (tx, mut queues) = lanes(8);
+        let (_stop, stop) = oneshot::channel();
+        let (done, _) = oneshot::channel();
+        tokio::spawn(rcvr.recv_into_chan(tx, stop, done));
+
+        let sent = Traffic::default();
+        for i in 0..4 {
+            let mut pkt = packet(format!("alarm {}", i));
+            pkt.priority = Priority::Critical;
+            write_frame(&mut write, &framing(None).encode(&pkt).unwrap(), &sent)
+                .await
+                .unwrap();
+        }
+        let mut got = Vec::new();
+        for _ in 0..4 {
+            let (lane, pkt) = poll_fn(|cx| {
+                for (lane, queue) in queues.iter_mut().enumerate() {
+                    if let Poll::Ready(Some(pkt)) = queue.poll_recv(cx) {
+                        return Poll::Ready((lane, pkt));
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+            assert_eq!(lane, pkt.priority.lane());
+            got.push(pkt.priority);
+        }
+        got.sort_by_key(|p| p.lane());
+        assert_eq!(
+            got,
+            [
+                Priority::Critical,
+                Priority::Critical,
+                Priority::Normal,
+                Priority::Normal
+            ]
+        );
+    }
+}
@@ -1,39 +1,162 @@
-use std::{collections::HashSet, fmt::Debug, net::SocketAddr};
+use std::{fmt::Debug, net::SocketAddr};
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+use crate::{
+    blob::BlobMsg, causal::VectorClock, dedup::now_millis, dht::DhtMsg, filter::BloomFilter,
+    linkstate::LinkStateAdvert, ordering::Seq, pubsub::Interest,
+};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum Operation {
     Broadcast {
-        seen: HashSet<SocketAddr>,
+        /// Nodes this packet has already passed through. This is a bloom
+        /// filter so the packet doesn't grow with every hop (or leak its route).
+        seen: BloomFilter,
         hops: u16,
     },
     Directed {
         target: SocketAddr,
     },
+    /// Meant for the neighbor that receives it only, never forwarded.
+    Link,
+    /// Published on a topic, forwarded towards the topic's subscribers.
+    Topic {
+        topic: String,
+    },
+}
+
+/// How urgent a packet is. More urgent packets overtake less urgent ones
+/// wherever they're queued, on the way out to a neighbor and on the way into
+/// the node.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Priority {
+    /// Alarms and the like, nothing waits in front of these.
+    Critical,
+    #[default]
+    Normal,
+    /// Telemetry, bulk transfers: whatever can wait.
+    Bulk,
+}
+
+impl Priority {
+    /// Most urgent first.
+    pub const ALL: [Priority; 3] = [Priority::Critical, Priority::Normal, Priority::Bulk];
+
+    /// Where this priority's queue is in a list of them ordered like `ALL`.
+    pub fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+/// Plumtree maintenance messages, exchanged between neighbors over `Link`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum GossipMsg {
+    /// "I have these messages", sent to lazy peers instead of the message.
+    IHave(Vec<Uuid>),
+    /// "Send me this message, and add me back to your eager set".
+    Graft(Uuid),
+    /// "You're sending me duplicates, stop eagerly pushing to me".
+    Prune,
+}
+
+/// Messages the nodes exchange among themselves to keep the network running.
+/// These are handled entirely inside the node, the application never sees
+/// them.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum ControlMsg {
+    /// Acknowledges a `Payload::Reliable` by its id.
+    Ack(Uuid),
+    Gossip(GossipMsg),
+    /// A distance vector: every node the sender can reach, and how many hops
+    /// away it is.
+    Routes(Vec<(SocketAddr, u16)>),
+    /// Flooded hop by hop, each node keeps the newest one from every origin.
+    LinkState(LinkStateAdvert),
+    Ping(u64),
+    Pong(u64),
+    /// Sent on links that have been quiet for a while, so the other end
+    /// knows we're still there.
+    Heartbeat,
+    /// The last thing a node sends on each link when shutting down.
+    Goodbye,
+    Dht(DhtMsg),
+    /// Flooded hop by hop, tells everyone which topics a node subscribes to.
+    Interest(Interest),
+    /// Sent to a neighbor on connecting: the ids of the recent broadcasts we
+    /// have, so it can replay the ones we missed while we were away. Only
+    /// broadcasts sent after `since` (ms since the unix epoch) are wanted.
+    Summary {
+        since: u64,
+        have: BloomFilter,
+    },
+    Blob(BlobMsg),
+    /// Periodic anti-entropy: a hash of the recent broadcast ids the sender
+    /// has, per bucket (see `History::digest`).
+    Digest(Vec<u128>),
+    /// Reply to a digest: our ids in the buckets where it didn't match ours.
+    /// The receiver sends back what we're missing and pulls the rest.
+    DigestIds {
+        buckets: Vec<u8>,
+        ids: Vec<Uuid>,
+    },
+    /// "Send me these broadcasts from your history".
+    Pull(Vec<Uuid>),
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum Payload<T> {
     Message(T),
+    /// An application message that the receiver has to acknowledge. `id`
+    /// stays the same across retransmissions, unlike the packet id.
+    Reliable {
+        id: Uuid,
+        msg: T,
+    },
+    /// A request for the target's handler, answered with a `Reply`.
+    Request(T),
+    /// The answer to the request packet `to`. None if the target had no
+    /// handler to answer with.
+    Reply {
+        to: Uuid,
+        msg: Option<T>,
+    },
+    Control(ControlMsg),
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct Packet<T> {
     pub id: Uuid,
     pub sender: SocketAddr,
+    /// When the packet was first sent, in ms since the unix epoch. Receivers
+    /// only remember ids for so long, so they refuse anything older.
+    pub sent_at: u64,
     pub op: Operation,
+    /// Where this packet falls among the broadcasts its sender has made, so
+    /// receivers can put them back in order. None on everything that isn't an
+    /// application broadcast, and on critical ones, which never wait.
+    pub seq: Option<Seq>,
+    /// What the sender had delivered when it made this broadcast, for
+    /// causal delivery. Only set by nodes running in causal mode.
+    pub clock: Option<VectorClock>,
+    /// Which queues the packet goes in. Set by its sender and kept as it's
+    /// forwarded, though each node only takes so many critical packets from
+    /// a neighbor before treating the rest as normal.
+    pub priority: Priority,
     pub payload: Payload<T>,
 }
 
 impl<T> Packet<T> {
-    #[allow(dead_code)]
     pub fn new(op: Operation, sender: SocketAddr, payload: Payload<T>) -> Self {
         Self {
             id: Uuid::new_v4(),
             sender,
+            sent_at: now_millis(),
             op,
+            seq: None,
+            clock: None,
+            priority: Priority::Normal,
             payload,
         }
     }
@@ -0,0 +1,225 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// A node's full set of subscribed topics. Flooded hop by hop through the
+/// mesh, and the link each one arrives on is remembered as the way back to
+/// that subscriber.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Interest {
+    pub subscriber: SocketAddr,
+    /// Newer announcements from the same subscriber replace older ones.
+    pub seq: u64,
+    pub topics: Vec<String>,
+}
+
+struct Remote {
+    seq: u64,
+    topics: HashSet<String>,
+    /// The peer link this subscriber's announcement reached us on.
+    via: SocketAddr,
+    updated: Instant,
+}
+
+/// Who is interested in what. Published messages only travel down links that
+/// lead towards at least one subscriber of their topic.
+pub struct Subscriptions {
+    me: SocketAddr,
+    local: HashSet<String>,
+    seq: u64,
+    remote: HashMap<SocketAddr, Remote>,
+    max_age: Duration,
+}
+
+impl Subscriptions {
+    /// Remote interest that isn't re-announced within `max_age` is forgotten.
+    /// Our announcements count up from `epoch`, so the ones we send after a
+    /// restart still replace those from before it.
+    pub fn new(me: SocketAddr, max_age: Duration, epoch: u64) -> Self {
+        Self {
+            me,
+            local: Default::default(),
+            seq: epoch,
+            remote: Default::default(),
+            max_age,
+        }
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.local.contains(topic)
+    }
+
+    pub fn has_local(&self) -> bool {
+        !self.local.is_empty()
+    }
+
+    /// Returns our new announcement if that changed anything.
+    pub fn subscribe(&mut self, topic: String) -> Option<Interest> {
+        if self.local.insert(topic) {
+            Some(self.announce())
+        } else {
+            None
+        }
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) -> Option<Interest> {
+        if self.local.remove(topic) {
+            Some(self.announce())
+        } else {
+            None
+        }
+    }
+
+    /// A fresh announcement of our local subscriptions.
+    pub fn announce(&mut self) -> Interest {
+        self.seq += 1;
+        self.current()
+    }
+
+    /// Our latest announcement, as already sent to the rest of the network.
+    pub fn current(&self) -> Interest {
+        Interest {
+            subscriber: self.me,
+            seq: self.seq,
+            topics: self.local.iter().cloned().collect(),
+        }
+    }
+
+    /// Record an announcement that came in on `via`. Returns true if it was
+    /// news to us, meaning it should be passed on to the other neighbors.
+    pub fn learn(&mut self, interest: &Interest, via: SocketAddr) -> bool {
+        if interest.subscriber == self.me {
+            return false;
+        }
+        if let Some(known) = self.remote.get(&interest.subscriber) {
+            if known.seq >= interest.seq {
+                return false;
+            }
+        }
+        self.remote.insert(
+            interest.subscriber,
+            Remote {
+                seq: interest.seq,
+                topics: interest.topics.iter().cloned().collect(),
+                via,
+                updated: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Everything we know, for bringing a new neighbor up to speed.
+    pub fn known(&self) -> Vec<Interest> {
+        self.remote
+            .iter()
+            .map(|(subscriber, r)| Interest {
+                subscriber: *subscriber,
+                seq: r.seq,
+                topics: r.topics.iter().cloned().collect(),
+            })
+            .collect()
+    }
+
+    /// The links a message on `topic` needs to go down (other than `from`).
+    pub fn next_hops(&self, topic: &str, from: Option<SocketAddr>) -> HashSet<SocketAddr> {
+        self.remote
+            .values()
+            .filter(|r| r.topics.contains(topic) && Some(r.via) != from)
+            .map(|r| r.via)
+            .collect()
+    }
+
+    /// The link `via` is gone, anyone we reached through it will show up again
+    /// on another link when they next announce.
+    pub fn link_down(&mut self, via: &SocketAddr) {
+        self.remote.retain(|_, r| r.via != *via);
+    }
+
+    pub fn expire(&mut self) {
+        let max_age = self.max_age;
+        self.remote.retain(|_, r| r.updated.elapsed() <= max_age);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    fn subs(port: u16, epoch: u64) -> Subscriptions {
+        Subscriptions::new(addr(port), Duration::from_secs(60), epoch)
+    }
+
+    #[test]
+    fn only_newer_announcements_are_news() {
+        let mut one = subs(1, 100);
+        let mut two = subs(2, 0);
+        let old = one.subscribe("a".into()).unwrap();
+        let new = one.subscribe("b".into()).unwrap();
+        assert!(one.subscribe("b".into()).is_none());
+        assert!(two.learn(&new, addr(1)));
+        assert!(!two.learn(&new, addr(1)));
+        assert!(!two.learn(&old, addr(1)));
+        assert_eq!(two.next_hops("a", None), HashSet::from([addr(1)]));
+        // our own announcements coming back around aren't news either
+        assert!(!one.learn(&new, addr(2)));
+    }
+
+    #[test]
+    fn announcements_after_a_restart_replace_the_old_ones() {
+        let mut two = subs(2, 0);
+        let mut before = subs(1, 1_000);
+        for topic in ["a", "b", "c"] {
+            two.learn(&before.subscribe(topic.into()).unwrap(), addr(1));
+        }
+        // back up a moment later with nothing subscribed yet
+        let mut after = subs(1, 1_010);
+        let interest = after.subscribe("d".into()).unwrap();
+        assert!(two.learn(&interest, addr(1)));
+        assert!(two.next_hops("a", None).is_empty());
+        assert_eq!(two.next_hops("d", None), HashSet::from([addr(1)]));
+    }
+
+    #[test]
+    fn unsubscribing_takes_the_topic_out_of_the_announcement() {
+        let mut one = subs(1, 0);
+        let mut two = subs(2, 0);
+        two.learn(&one.subscribe("a".into()).unwrap(), addr(1));
+        assert!(one.unsubscribe("b").is_none());
+        let interest = one.unsubscribe("a").unwrap();
+        assert!(interest.topics.is_empty());
+        assert!(!one.has_local());
+        assert!(two.learn(&interest, addr(1)));
+        assert!(two.next_hops("a", None).is_empty());
+    }
+
+    #[test]
+    fn messages_go_towards_subscribers_but_not_back() {
+        let mut me = subs(1, 0);
+        for port in [2, 3, 4] {
+            let mut other = subs(port, 0);
+            let topic = if port == 4 { "other" } else { "a" };
+            me.learn(&other.subscribe(topic.into()).unwrap(), addr(port));
+        }
+        assert_eq!(me.next_hops("a", None), HashSet::from([addr(2), addr(3)]));
+        assert_eq!(me.next_hops("a", Some(addr(2))), HashSet::from([addr(3)]));
+        me.link_down(&addr(3));
+        assert_eq!(me.next_hops("a", None), HashSet::from([addr(2)]));
+        assert_eq!(me.known().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn interest_that_is_not_renewed_is_forgotten() {
+        tokio::time::pause();
+        let mut me = Subscriptions::new(addr(1), Duration::ZERO, 0);
+        me.learn(&subs(2, 0).subscribe("a".into()).unwrap(), addr(2));
+        tokio::time::advance(Duration::from_millis(5)).await;
+        me.expire();
+        assert!(me.known().is_empty());
+    }
+}
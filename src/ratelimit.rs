@@ -0,0 +1,200 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use tokio::time::Instant;
+
+/// A sustained rate in frames per second, with room for bursts of up to
+/// `burst` frames on top of it.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub rate: u32,
+    pub burst: u32,
+}
+
+/// What happens to a peer that goes over its own limit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverLimit {
+    /// Throw away whatever doesn't fit, keep the link.
+    Drop,
+    /// Hang up on it.
+    Disconnect,
+}
+
+/// Starts full, every frame takes a token, and tokens come back at the
+/// limit's rate.
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.limit.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.limit.burst as f64);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What to do with an inbound frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    Admit,
+    Drop,
+    Disconnect,
+}
+
+/// Limits on frames coming in over all of a node's links, shared by their
+/// reader tasks so excess traffic is thrown away before it gets anywhere
+/// near the node.
+pub struct InboundLimits {
+    per_peer: Option<RateLimit>,
+    over_limit: OverLimit,
+    global: Option<Mutex<TokenBucket>>,
+    critical: RateLimit,
+    /// Frames dropped for going over a limit.
+    pub dropped: AtomicU64,
+    /// Links hung up on for going over their limit.
+    pub disconnects: AtomicU64,
+}
+
+impl InboundLimits {
+    pub fn new(
+        per_peer: Option<RateLimit>,
+        global: Option<RateLimit>,
+        over_limit: OverLimit,
+        critical: RateLimit,
+    ) -> Self {
+        Self {
+            per_peer,
+            over_limit,
+            global: global.map(|limit| Mutex::new(TokenBucket::new(limit))),
+            critical,
+            dropped: AtomicU64::new(0),
+            disconnects: AtomicU64::new(0),
+        }
+    }
+
+    /// A fresh bucket for a new link, if peers are limited.
+    pub fn peer_bucket(&self) -> Option<TokenBucket> {
+        self.per_peer.map(TokenBucket::new)
+    }
+
+    /// A fresh bucket for the critical packets of a new link.
+    pub fn critical_bucket(&self) -> TokenBucket {
+        TokenBucket::new(self.critical)
+    }
+
+    /// Check a frame that came in on the link `bucket` belongs to. A peer
+    /// over its own limit gets `over_limit`, but going over the global one
+    /// only ever drops the frame, as that's not down to any one peer.
+    pub fn admit(&self, bucket: &mut Option<TokenBucket>) -> Verdict {
+        if let Some(bucket) = bucket {
+            if !bucket.take() {
+                return match self.over_limit {
+                    OverLimit::Drop => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        Verdict::Drop
+                    }
+                    OverLimit::Disconnect => {
+                        self.disconnects.fetch_add(1, Ordering::Relaxed);
+                        Verdict::Disconnect
+                    }
+                };
+            }
+        }
+        if let Some(global) = &self.global {
+            if !global.lock().unwrap().take() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Verdict::Drop;
+            }
+        }
+        Verdict::Admit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limit(rate: u32, burst: u32) -> RateLimit {
+        RateLimit { rate, burst }
+    }
+
+    #[test]
+    fn buckets_start_full_and_run_dry() {
+        let mut bucket = TokenBucket::new(limit(1, 3));
+        for _ in 0..3 {
+            assert!(bucket.take());
+        }
+        assert!(!bucket.take());
+    }
+
+    #[tokio::test]
+    async fn buckets_refill_up_to_the_burst() {
+        tokio::time::pause();
+        let mut bucket = TokenBucket::new(limit(1000, 2));
+        while bucket.take() {}
+        tokio::time::advance(Duration::from_millis(20)).await;
+        // 20 tokens' worth of time, but only room for 2
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn a_peer_over_its_limit_gets_what_the_config_says() {
+        for (over_limit, verdict) in [
+            (OverLimit::Drop, Verdict::Drop),
+            (OverLimit::Disconnect, Verdict::Disconnect),
+        ] {
+            let limits = InboundLimits::new(Some(limit(1, 1)), None, over_limit, limit(1, 1));
+            let mut bucket = limits.peer_bucket();
+            assert_eq!(limits.admit(&mut bucket), Verdict::Admit);
+            assert_eq!(limits.admit(&mut bucket), verdict);
+            // another peer has its own bucket
+            assert_eq!(limits.admit(&mut limits.peer_bucket()), Verdict::Admit);
+        }
+    }
+
+    #[test]
+    fn the_global_limit_only_drops() {
+        let limits =
+            InboundLimits::new(None, Some(limit(1, 2)), OverLimit::Disconnect, limit(1, 1));
+        let (mut a, mut b) = (limits.peer_bucket(), limits.peer_bucket());
+        assert!(a.is_none());
+        assert_eq!(limits.admit(&mut a), Verdict::Admit);
+        assert_eq!(limits.admit(&mut b), Verdict::Admit);
+        assert_eq!(limits.admit(&mut a), Verdict::Drop);
+        assert_eq!(limits.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(limits.disconnects.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn no_limits_admit_everything() {
+        let limits = InboundLimits::new(None, None, OverLimit::Disconnect, limit(1, 1));
+        let mut bucket = limits.peer_bucket();
+        for _ in 0..10_000 {
+            assert_eq!(limits.admit(&mut bucket), Verdict::Admit);
+        }
+    }
+}
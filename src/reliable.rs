@@ -0,0 +1,222 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::{sync::oneshot, time::Instant};
+use uuid::Uuid;
+
+use crate::{node::NodeStopped, ordering::Seq};
+
+/// How a reliable broadcast went.
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryReport {
+    /// Nodes that acknowledged the message.
+    pub confirmed: HashSet<SocketAddr>,
+    /// Nodes that still hadn't when the deadline passed.
+    pub missing: HashSet<SocketAddr>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DeliveryError {
+    /// No targets were named and we don't know of any other node, so there
+    /// was nobody to deliver to.
+    NoTargets,
+    /// Our own node shut down before the broadcast finished.
+    NodeStopped(NodeStopped),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::NoTargets => write!(f, "no nodes to deliver to"),
+            DeliveryError::NodeStopped(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+impl From<NodeStopped> for DeliveryError {
+    fn from(e: NodeStopped) -> Self {
+        DeliveryError::NodeStopped(e)
+    }
+}
+
+struct Outgoing<M> {
+    msg: M,
+    seq: Option<Seq>,
+    report: DeliveryReport,
+    deadline: Instant,
+    next_retry: Instant,
+    attempt: usize,
+    reply: oneshot::Sender<Result<DeliveryReport, DeliveryError>>,
+}
+
+/// A retransmission to make: send `msg` (reliable message `id`) to `target`.
+/// `attempt` counts up from 1, so the node can pick a different path each time.
+pub struct Retry<M> {
+    pub id: Uuid,
+    pub target: SocketAddr,
+    pub msg: M,
+    /// The broadcast sequence number the message first went out with.
+    pub seq: Option<Seq>,
+    pub attempt: usize,
+}
+
+/// Reliable messages we've sent that some targets haven't acknowledged yet.
+pub struct ReliableSender<M> {
+    outgoing: HashMap<Uuid, Outgoing<M>>,
+    retry_interval: Duration,
+}
+
+impl<M: Clone> ReliableSender<M> {
+    pub fn new(retry_interval: Duration) -> Self {
+        Self {
+            outgoing: Default::default(),
+            retry_interval,
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        id: Uuid,
+        msg: M,
+        seq: Option<Seq>,
+        targets: HashSet<SocketAddr>,
+        deadline: Instant,
+        reply: oneshot::Sender<Result<DeliveryReport, DeliveryError>>,
+    ) {
+        if targets.is_empty() {
+            let _ = reply.send(Err(DeliveryError::NoTargets));
+            return;
+        }
+        let report = DeliveryReport {
+            confirmed: Default::default(),
+            missing: targets,
+        };
+        self.outgoing.insert(
+            id,
+            Outgoing {
+                msg,
+                seq,
+                report,
+                deadline,
+                next_retry: Instant::now() + self.retry_interval,
+                attempt: 0,
+                reply,
+            },
+        );
+    }
+
+    /// `from` got message `id`. Reports back once everyone has.
+    pub fn ack(&mut self, id: &Uuid, from: SocketAddr) {
+        let done = match self.outgoing.get_mut(id) {
+            Some(out) => {
+                if out.report.missing.remove(&from) {
+                    out.report.confirmed.insert(from);
+                }
+                out.report.missing.is_empty()
+            }
+            None => false,
+        };
+        if done {
+            let out = self.outgoing.remove(id).expect("just found it");
+            let _ = out.reply.send(Ok(out.report));
+        }
+    }
+
+    /// Gives up on messages that are past their deadline, and returns the
+    /// retransmissions that are due for the rest.
+    pub fn due(&mut self) -> Vec<Retry<M>> {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .outgoing
+            .iter()
+            .filter(|(_, o)| o.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let out = self.outgoing.remove(&id).expect("just found it");
+            let _ = out.reply.send(Ok(out.report));
+        }
+
+        let mut retries = Vec::new();
+        for (id, out) in self.outgoing.iter_mut() {
+            if out.next_retry > now {
+                continue;
+            }
+            out.attempt += 1;
+            out.next_retry = now + self.retry_interval;
+            for target in &out.report.missing {
+                retries.push(Retry {
+                    id: *id,
+                    target: *target,
+                    msg: out.msg.clone(),
+                    seq: out.seq,
+                    attempt: out.attempt,
+                });
+            }
+        }
+        retries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    type Reply = oneshot::Receiver<Result<DeliveryReport, DeliveryError>>;
+
+    fn start(sender: &mut ReliableSender<u32>, ports: &[u16], timeout: Duration) -> (Uuid, Reply) {
+        let id = Uuid::new_v4();
+        let targets = ports.iter().map(|p| addr(*p)).collect();
+        let (tx, rx) = oneshot::channel();
+        sender.start(id, 7, None, targets, Instant::now() + timeout, tx);
+        (id, rx)
+    }
+
+    #[test]
+    fn nobody_to_deliver_to_is_an_error() {
+        let mut sender = ReliableSender::new(Duration::from_secs(1));
+        let (_, mut rx) = start(&mut sender, &[], Duration::from_secs(1));
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap_err(),
+            DeliveryError::NoTargets
+        );
+    }
+
+    #[test]
+    fn reports_once_everyone_acked() {
+        let mut sender = ReliableSender::new(Duration::from_secs(1));
+        let (id, mut rx) = start(&mut sender, &[2, 3], Duration::from_secs(60));
+        sender.ack(&id, addr(2));
+        // acks from nodes we didn't send to don't count
+        sender.ack(&id, addr(4));
+        assert!(rx.try_recv().is_err());
+        sender.ack(&id, addr(3));
+        let report = rx.try_recv().unwrap().unwrap();
+        assert_eq!(report.confirmed, HashSet::from([addr(2), addr(3)]));
+        assert!(report.missing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_targets_are_retried_until_the_deadline() {
+        tokio::time::pause();
+        let mut sender = ReliableSender::new(Duration::ZERO);
+        let (id, mut rx) = start(&mut sender, &[2, 3], Duration::from_millis(50));
+        sender.ack(&id, addr(2));
+        let retries = sender.due();
+        assert_eq!(retries.len(), 1);
+        assert_eq!((retries[0].target, retries[0].attempt), (addr(3), 1));
+        assert_eq!(sender.due()[0].attempt, 2);
+
+        tokio::time::advance(Duration::from_millis(60)).await;
+        assert!(sender.due().is_empty());
+        let report = rx.try_recv().unwrap().unwrap();
+        assert_eq!(report.missing, HashSet::from([addr(3)]));
+    }
+}
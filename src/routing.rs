@@ -0,0 +1,290 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// How a node works out where to send directed packets.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum RoutingMode {
+    /// Neighbors exchange distance vectors, nobody sees the whole network.
+    #[default]
+    DistanceVector,
+    /// Every node floods its links and runs shortest path over the full
+    /// topology, with link costs from measured round trip times.
+    LinkState,
+}
+
+/// Metric that means "unreachable" (RIP style). Keeps count-to-infinity short.
+pub const INFINITY: u16 = 16;
+
+/// A route to some node in the network.
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub dest: SocketAddr,
+    /// The peer link packets for `dest` should be sent down.
+    pub next_hop: SocketAddr,
+    /// Hop count, `INFINITY` if the route has been poisoned.
+    pub hops: u16,
+    /// Total measured round trip time along the route, in ms. Only link state
+    /// routing measures links, distance vector routes just count hops.
+    pub cost: Option<u32>,
+    pub updated: Instant,
+}
+
+/// A distance-vector routing table. Neighbors periodically tell us their
+/// distance to every node they know of, and we keep the best next hop.
+pub struct RoutingTable {
+    me: SocketAddr,
+    routes: HashMap<SocketAddr, Route>,
+    timeout: Duration,
+    /// Set when a route got poisoned, so neighbors hear about it right away
+    /// instead of at the next periodic advertisement.
+    triggered: bool,
+}
+
+impl RoutingTable {
+    /// Routes that haven't been refreshed in `timeout` are poisoned, and
+    /// removed entirely after another `timeout`.
+    pub fn new(me: SocketAddr, timeout: Duration) -> Self {
+        Self {
+            me,
+            routes: Default::default(),
+            timeout,
+            triggered: false,
+        }
+    }
+
+    pub fn next_hop(&self, dest: &SocketAddr) -> Option<SocketAddr> {
+        self.routes
+            .get(dest)
+            .filter(|r| r.hops < INFINITY)
+            .map(|r| r.next_hop)
+    }
+
+    pub fn routes(&self) -> Vec<Route> {
+        self.routes.values().cloned().collect()
+    }
+
+    /// Merge an advertisement that `neighbor` sent us over the link `via`.
+    pub fn update(&mut self, via: SocketAddr, neighbor: SocketAddr, entries: &[(SocketAddr, u16)]) {
+        let now = Instant::now();
+        let direct = std::iter::once((neighbor, 0));
+        for (dest, metric) in direct.chain(entries.iter().copied()) {
+            if dest == self.me {
+                continue;
+            }
+            let metric = metric.saturating_add(1).min(INFINITY);
+            match self.routes.get_mut(&dest) {
+                // whatever our next hop says goes, even if it got worse
+                Some(route) if route.next_hop == via => {
+                    if metric >= INFINITY && route.hops < INFINITY {
+                        self.triggered = true;
+                    }
+                    // don't let a poisoned route keep itself alive forever
+                    if metric < INFINITY || route.hops < INFINITY {
+                        route.updated = now;
+                    }
+                    route.hops = metric;
+                }
+                Some(route) if metric < route.hops => {
+                    *route = Route {
+                        dest,
+                        next_hop: via,
+                        hops: metric,
+                        cost: None,
+                        updated: now,
+                    };
+                }
+                Some(_) => {}
+                None if metric < INFINITY => {
+                    self.routes.insert(
+                        dest,
+                        Route {
+                            dest,
+                            next_hop: via,
+                            hops: metric,
+                            cost: None,
+                            updated: now,
+                        },
+                    );
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// The link `via` went away, poison everything we were sending down it.
+    pub fn link_down(&mut self, via: &SocketAddr) {
+        let now = Instant::now();
+        for route in self.routes.values_mut() {
+            if route.next_hop == *via && route.hops < INFINITY {
+                route.hops = INFINITY;
+                route.updated = now;
+                self.triggered = true;
+            }
+        }
+    }
+
+    /// Poison stale routes and forget about long dead ones.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let timeout = self.timeout;
+        for route in self.routes.values_mut() {
+            if route.hops < INFINITY && now.duration_since(route.updated) > timeout {
+                route.hops = INFINITY;
+                route.updated = now;
+                self.triggered = true;
+            }
+        }
+        self.routes
+            .retain(|_, r| r.hops < INFINITY || now.duration_since(r.updated) <= timeout);
+    }
+
+    /// Returns true (once) if something was poisoned since the last call.
+    pub fn take_triggered(&mut self) -> bool {
+        std::mem::replace(&mut self.triggered, false)
+    }
+
+    /// The advertisement to send down `via`. Uses split horizon with poisoned
+    /// reverse: routes that go through `via` are advertised back as unreachable.
+    pub fn advertisement(&self, via: &SocketAddr) -> Vec<(SocketAddr, u16)> {
+        self.routes
+            .values()
+            .map(|r| {
+                let metric = if r.next_hop == *via { INFINITY } else { r.hops };
+                (r.dest, metric)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    /// Routing tables for nodes `1..=n`, where each node's link to a neighbor
+    /// goes by the neighbor's address.
+    fn tables(n: u16) -> HashMap<SocketAddr, RoutingTable> {
+        (1..=n)
+            .map(|port| {
+                (
+                    addr(port),
+                    RoutingTable::new(addr(port), Duration::from_secs(60)),
+                )
+            })
+            .collect()
+    }
+
+    /// `from` advertises its routes to `to`.
+    fn advertise(tables: &mut HashMap<SocketAddr, RoutingTable>, from: u16, to: u16) {
+        let entries = tables[&addr(from)].advertisement(&addr(to));
+        tables
+            .get_mut(&addr(to))
+            .unwrap()
+            .update(addr(from), addr(from), &entries);
+    }
+
+    fn hops(tables: &HashMap<SocketAddr, RoutingTable>, at: u16, dest: u16) -> Option<u16> {
+        tables[&addr(at)]
+            .routes()
+            .into_iter()
+            .find(|r| r.dest == addr(dest))
+            .map(|r| r.hops)
+    }
+
+    #[test]
+    fn learns_routes_through_neighbors() {
+        // 1 - 2 - 3
+        let mut t = tables(3);
+        advertise(&mut t, 3, 2);
+        advertise(&mut t, 2, 1);
+        assert_eq!(hops(&t, 1, 2), Some(1));
+        assert_eq!(hops(&t, 1, 3), Some(2));
+        assert_eq!(t[&addr(1)].next_hop(&addr(3)), Some(addr(2)));
+    }
+
+    #[test]
+    fn split_horizon_poisons_the_reverse_route() {
+        let mut t = tables(3);
+        advertise(&mut t, 3, 2);
+        advertise(&mut t, 2, 1);
+        // 1 reaches 3 through 2, so it tells 2 it can't reach 3 at all
+        let to_two = t[&addr(1)].advertisement(&addr(2));
+        assert!(to_two.contains(&(addr(3), INFINITY)));
+        assert!(to_two.contains(&(addr(2), INFINITY)));
+        // but it doesn't hide the route from anyone else
+        let to_four = t[&addr(1)].advertisement(&addr(4));
+        assert!(to_four.contains(&(addr(3), 2)));
+    }
+
+    #[test]
+    fn broken_link_is_not_routed_back_around() {
+        // 1 - 2 - 3, then 2 - 3 breaks
+        let mut t = tables(3);
+        advertise(&mut t, 3, 2);
+        advertise(&mut t, 2, 1);
+        t.get_mut(&addr(2)).unwrap().link_down(&addr(3));
+        assert!(t.get_mut(&addr(2)).unwrap().take_triggered());
+        // without poisoned reverse 1 would offer 2 its stale route to 3 here
+        advertise(&mut t, 1, 2);
+        assert_eq!(t[&addr(2)].next_hop(&addr(3)), None);
+        advertise(&mut t, 2, 1);
+        assert_eq!(hops(&t, 1, 3), Some(INFINITY));
+        assert_eq!(t[&addr(1)].next_hop(&addr(3)), None);
+    }
+
+    /// Split horizon can't stop a loop of three from counting to infinity,
+    /// but INFINITY keeps the count short.
+    #[test]
+    fn count_to_infinity_stops_at_infinity() {
+        // 1, 2 and 3 all linked, 4 hangs off 3
+        let mut t = tables(4);
+        advertise(&mut t, 4, 3);
+        for (from, to) in [(3, 1), (3, 2), (1, 2), (2, 1)] {
+            advertise(&mut t, from, to);
+        }
+        assert_eq!(hops(&t, 1, 4), Some(2));
+        assert_eq!(hops(&t, 2, 4), Some(2));
+
+        t.get_mut(&addr(3)).unwrap().link_down(&addr(4));
+        // the worst order: 3's news only reaches 1, and 2 keeps telling 1
+        // about its route through 3 before hearing about it
+        advertise(&mut t, 3, 1);
+        advertise(&mut t, 2, 1);
+        assert_eq!(hops(&t, 1, 4), Some(3));
+
+        let mut rounds = 0;
+        while [1, 2, 3]
+            .iter()
+            .any(|n| t[&addr(*n)].next_hop(&addr(4)).is_some())
+        {
+            for (from, to) in [(1, 3), (3, 2), (2, 1), (1, 2), (2, 3), (3, 1)] {
+                advertise(&mut t, from, to);
+            }
+            rounds += 1;
+            assert!(
+                rounds <= INFINITY as usize,
+                "still counting after {} rounds",
+                rounds
+            );
+        }
+        for n in [1, 2, 3] {
+            assert_eq!(hops(&t, n, 4), Some(INFINITY));
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_routes_are_poisoned_then_forgotten() {
+        tokio::time::pause();
+        let mut table = RoutingTable::new(addr(1), Duration::ZERO);
+        table.update(addr(2), addr(2), &[(addr(3), 1)]);
+        tokio::time::advance(Duration::from_millis(5)).await;
+        table.expire();
+        assert!(table.take_triggered());
+        assert!(table.routes().iter().all(|r| r.hops == INFINITY));
+        tokio::time::advance(Duration::from_millis(5)).await;
+        table.expire();
+        assert!(table.routes().is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashMap, fmt, net::SocketAddr};
+
+use tokio::{sync::oneshot, time::Instant};
+use uuid::Uuid;
+
+use crate::node::NodeStopped;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RpcError {
+    /// No reply before the deadline. The request may or may not have been
+    /// handled on the other end.
+    Timeout,
+    /// We don't know of any way to get to the target node.
+    NoRoute,
+    /// The target got the request but has no handler registered.
+    NoHandler,
+    /// Our own node shut down before the call finished.
+    NodeStopped(NodeStopped),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "request timed out"),
+            RpcError::NoRoute => write!(f, "no route to target node"),
+            RpcError::NoHandler => write!(f, "target node has no request handler"),
+            RpcError::NodeStopped(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<NodeStopped> for RpcError {
+    fn from(e: NodeStopped) -> Self {
+        RpcError::NodeStopped(e)
+    }
+}
+
+/// Answers requests from other nodes: gets the requester's address and the
+/// request, returns the reply. Runs on the node's own task, so keep it quick.
+pub type RequestHandler<M> = Box<dyn FnMut(SocketAddr, M) -> M + Send>;
+
+struct Call<M> {
+    /// Who we asked, the only node whose answer counts.
+    target: SocketAddr,
+    deadline: Instant,
+    reply: oneshot::Sender<Result<M, RpcError>>,
+}
+
+/// Requests we've sent and are still waiting on, keyed by the id of the
+/// request packet (which the reply refers back to).
+pub struct PendingCalls<M> {
+    calls: HashMap<Uuid, Call<M>>,
+}
+
+impl<M> Default for PendingCalls<M> {
+    fn default() -> Self {
+        Self {
+            calls: Default::default(),
+        }
+    }
+}
+
+impl<M> PendingCalls<M> {
+    pub fn insert(
+        &mut self,
+        id: Uuid,
+        target: SocketAddr,
+        deadline: Instant,
+        reply: oneshot::Sender<Result<M, RpcError>>,
+    ) {
+        let call = Call {
+            target,
+            deadline,
+            reply,
+        };
+        self.calls.insert(id, call);
+    }
+
+    /// `from` answered request `id`. Answers from anyone but the node we
+    /// asked are ignored.
+    pub fn complete(&mut self, id: &Uuid, from: SocketAddr, result: Result<M, RpcError>) {
+        if self.calls.get(id).is_some_and(|c| c.target == from) {
+            self.finish(id, result);
+        }
+    }
+
+    fn finish(&mut self, id: &Uuid, result: Result<M, RpcError>) {
+        if let Some(call) = self.calls.remove(id) {
+            let _ = call.reply.send(result);
+        }
+    }
+
+    /// Fail every call whose deadline has passed.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .calls
+            .iter()
+            .filter(|(_, c)| c.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.finish(&id, Err(RpcError::Timeout));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::addr;
+
+    #[test]
+    fn only_the_called_node_can_answer() {
+        let mut calls = PendingCalls::default();
+        let id = Uuid::new_v4();
+        let (tx, mut rx) = oneshot::channel();
+        calls.insert(
+            id,
+            addr(2),
+            Instant::now() + std::time::Duration::from_secs(60),
+            tx,
+        );
+        calls.complete(&id, addr(3), Ok("forged"));
+        assert!(rx.try_recv().is_err());
+        calls.complete(&id, addr(2), Ok("real"));
+        assert_eq!(rx.try_recv(), Ok(Ok("real")));
+        // and only once
+        calls.complete(&id, addr(2), Ok("again"));
+    }
+
+    #[test]
+    fn unanswered_calls_time_out() {
+        let mut calls = PendingCalls::<u32>::default();
+        let id = Uuid::new_v4();
+        let (tx, mut rx) = oneshot::channel();
+        calls.insert(id, addr(2), Instant::now(), tx);
+        calls.expire();
+        assert_eq!(rx.try_recv(), Ok(Err(RpcError::Timeout)));
+    }
+}
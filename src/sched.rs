@@ -0,0 +1,235 @@
+use std::{
+    net::SocketAddr,
+    task::{Context, Poll},
+};
+
+use futures::future::poll_fn;
+use tokio::sync::mpsc;
+
+use crate::proto::Priority;
+
+/// Takes from a set of per-link queues in turn, one item from each, so a
+/// link with a lot to say can't crowd out the others. Queues whose senders
+/// are all gone are dropped once they're empty.
+pub struct FairQueue<T> {
+    queues: Vec<(SocketAddr, mpsc::Receiver<T>)>,
+    /// Where the next turn starts.
+    next: usize,
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> Self {
+        Self {
+            queues: Vec::new(),
+            next: 0,
+        }
+    }
+}
+
+impl<T> FairQueue<T> {
+    /// Start taking from `rx`, in place of any queue `link` had before.
+    pub fn add(&mut self, link: SocketAddr, rx: mpsc::Receiver<T>) {
+        self.remove(&link);
+        self.queues.push((link, rx));
+    }
+
+    /// Stop taking from `link`'s queue, throwing away whatever is left in it.
+    pub fn remove(&mut self, link: &SocketAddr) {
+        self.queues.retain(|(addr, _)| addr != link);
+    }
+
+    /// The next item, from whichever link's turn it is that has one. Never
+    /// ready if there are no queues.
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<(SocketAddr, T)> {
+        let mut tried = 0;
+        while tried < self.queues.len() {
+            let i = (self.next + tried) % self.queues.len();
+            match self.queues[i].1.poll_recv(cx) {
+                Poll::Ready(Some(item)) => {
+                    self.next = i + 1;
+                    return Poll::Ready((self.queues[i].0, item));
+                }
+                Poll::Ready(None) => {
+                    // the link is gone and we've had everything from it
+                    self.queues.remove(i);
+                    if i < self.next {
+                        self.next -= 1;
+                    }
+                }
+                Poll::Pending => tried += 1,
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// A `FairQueue` per priority, for links that queue their packets by
+/// priority. Nothing is taken from a lane while a more urgent one has
+/// something waiting.
+pub struct PriorityLanes<T> {
+    lanes: Vec<FairQueue<T>>,
+}
+
+impl<T> Default for PriorityLanes<T> {
+    fn default() -> Self {
+        Self {
+            lanes: Priority::ALL.iter().map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+impl<T> PriorityLanes<T> {
+    /// Start taking from `link`'s queues, one per priority in the order of
+    /// `Priority::ALL`.
+    pub fn add(&mut self, link: SocketAddr, queues: Vec<mpsc::Receiver<T>>) {
+        for (lane, rx) in self.lanes.iter_mut().zip(queues) {
+            lane.add(link, rx);
+        }
+    }
+
+    pub fn remove(&mut self, link: &SocketAddr) {
+        for lane in &mut self.lanes {
+            lane.remove(link);
+        }
+    }
+
+    pub async fn recv(&mut self) -> (SocketAddr, T) {
+        poll_fn(|cx| {
+            for lane in &mut self.lanes {
+                if let Poll::Ready(item) = lane.poll_recv(cx) {
+                    return Poll::Ready(item);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// A bounded queue per priority, in the order of `Priority::ALL`.
+pub fn lanes<T>(len: usize) -> (Vec<mpsc::Sender<T>>, Vec<mpsc::Receiver<T>>) {
+    Priority::ALL.iter().map(|_| mpsc::channel(len)).unzip()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::addr;
+
+    /// Everything `queue` has ready right now, in the order it hands it out.
+    async fn drain<T>(queue: &mut FairQueue<T>) -> Vec<(SocketAddr, T)> {
+        let mut out = Vec::new();
+        loop {
+            let next = poll_fn(|cx| queue.poll_recv(cx));
+            match tokio::time::timeout(Duration::from_millis(20), next).await {
+                Ok(item) => out.push(item),
+                Err(_) => return out,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn links_take_turns() {
+        let mut queue = FairQueue::default();
+        let (chatty, rx) = mpsc::channel(16);
+        queue.add(addr(1), rx);
+        let (quiet, rx) = mpsc::channel(16);
+        queue.add(addr(2), rx);
+        for i in 0..5 {
+            chatty.send(i).await.unwrap();
+        }
+        quiet.send(10).await.unwrap();
+        quiet.send(11).await.unwrap();
+        let got = drain(&mut queue).await;
+        assert_eq!(
+            got,
+            vec![
+                (addr(1), 0),
+                (addr(2), 10),
+                (addr(1), 1),
+                (addr(2), 11),
+                (addr(1), 2),
+                (addr(1), 3),
+                (addr(1), 4),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn removed_links_are_forgotten() {
+        let mut queue = FairQueue::default();
+        let (a, rx) = mpsc::channel(16);
+        queue.add(addr(1), rx);
+        let (b, rx) = mpsc::channel(16);
+        queue.add(addr(2), rx);
+        a.send(1).await.unwrap();
+        b.send(2).await.unwrap();
+        queue.remove(&addr(1));
+        assert_eq!(drain(&mut queue).await, vec![(addr(2), 2)]);
+        assert!(a.send(3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn adding_a_link_again_replaces_its_queue() {
+        let mut queue = FairQueue::default();
+        let (old, rx) = mpsc::channel(16);
+        queue.add(addr(1), rx);
+        old.send(1).await.unwrap();
+        let (new, rx) = mpsc::channel(16);
+        queue.add(addr(1), rx);
+        new.send(2).await.unwrap();
+        assert_eq!(drain(&mut queue).await, vec![(addr(1), 2)]);
+    }
+
+    #[tokio::test]
+    async fn closed_queues_are_emptied_then_dropped() {
+        let mut queue = FairQueue::default();
+        let (gone, rx) = mpsc::channel(16);
+        queue.add(addr(1), rx);
+        let (stays, rx) = mpsc::channel(16);
+        queue.add(addr(2), rx);
+        gone.send(1).await.unwrap();
+        gone.send(2).await.unwrap();
+        drop(gone);
+        stays.send(3).await.unwrap();
+        assert_eq!(
+            drain(&mut queue).await,
+            vec![(addr(1), 1), (addr(2), 3), (addr(1), 2)]
+        );
+        assert_eq!(queue.queues.len(), 1);
+        stays.send(4).await.unwrap();
+        assert_eq!(drain(&mut queue).await, vec![(addr(2), 4)]);
+    }
+
+    #[tokio::test]
+    async fn urgent_lanes_go_first_whoever_sent_them() {
+        let mut lanes_in = PriorityLanes::default();
+        let (chatty, rx) = lanes(16);
+        lanes_in.add(addr(1), rx);
+        let (alarm, rx) = lanes(16);
+        lanes_in.add(addr(2), rx);
+        for i in 0..3 {
+            chatty[Priority::Bulk.lane()].send(i).await.unwrap();
+            chatty[Priority::Normal.lane()].send(10 + i).await.unwrap();
+        }
+        alarm[Priority::Critical.lane()].send(99).await.unwrap();
+        let mut got = Vec::new();
+        for _ in 0..7 {
+            got.push(lanes_in.recv().await);
+        }
+        assert_eq!(
+            got,
+            vec![
+                (addr(2), 99),
+                (addr(1), 10),
+                (addr(1), 11),
+                (addr(1), 12),
+                (addr(1), 0),
+                (addr(1), 1),
+                (addr(1), 2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,8 @@
+//! Helpers shared by the unit tests.
+
+use std::net::SocketAddr;
+
+/// A made up node on localhost, for tests that never open a socket.
+pub fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}